@@ -6,7 +6,8 @@ fn main() {
 
     let cfg = copperline::Config {
         encoding: copperline::Encoding::Utf8,
-        mode: copperline::EditMode::Vi
+        mode: copperline::EditMode::Vi,
+        ..copperline::Config::default()
     };
 
     let mut cl = Copperline::new();
@@ -6,6 +6,9 @@ use unicode_width::UnicodeWidthStr;
 use unicode_segmentation::UnicodeSegmentation;
 
 use builder::Builder;
+use highlight::{Span, paint};
+use prompt::{Prompt, display_width};
+use history::SearchDirection;
 
 #[derive(Debug,Clone,Copy,PartialEq,Eq,PartialOrd,Ord)]
 pub struct Position {
@@ -28,7 +31,7 @@ impl Position {
 
     pub fn set_to_end_of_str(&mut self, buf: &str) {
         self.byte_pos = buf.len();
-        self.char_pos = UnicodeWidthStr::width(buf);
+        self.char_pos = UnicodeSegmentation::graphemes(buf, true).count();
     }
 
     pub fn increase_by_char(&mut self, c: char) {
@@ -38,7 +41,7 @@ impl Position {
 
     pub fn decrease_by_str(&mut self, buf: &str) {
         self.byte_pos -= buf.len();
-        self.char_pos -= UnicodeWidthStr::width(buf);
+        self.char_pos -= UnicodeSegmentation::graphemes(buf, true).count();
     }
 
 }
@@ -229,6 +232,18 @@ impl Buffer {
         return false;
     }
 
+    /// Moves past the end of the next word, like `M-f` in emacs.
+    pub fn move_word_forward(&mut self) -> bool {
+        let start = self.pos;
+        while self.cp_after().is_some_and(|c| !c.is_alphanumeric()) {
+            self.move_right();
+        }
+        while self.cp_after().is_some_and(|c| c.is_alphanumeric()) {
+            self.move_right();
+        }
+        self.pos != start
+    }
+
     pub fn move_end(&mut self) {
         self.pos.set_to_end_of_str(self.front_buf.as_str());
     }
@@ -383,23 +398,233 @@ impl Buffer {
         }
     }
 
+    /// Moves to the line above, keeping the column where it can.
+    pub fn move_line_up(&mut self) -> bool {
+        let before = self.text_before_cursor();
+        match before.rfind('\n') {
+            Some(end) => {
+                let col = UnicodeWidthStr::width(&before[end + 1..]);
+                let start = before[..end].rfind('\n').map_or(0, |i| i + 1);
+                self.move_to_column(start, end, col);
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Moves to the line below, keeping the column where it can.
+    pub fn move_line_down(&mut self) -> bool {
+        let byte_pos = self.byte_pos();
+        match self.front_buf[byte_pos..].find('\n') {
+            Some(n) => {
+                let before = self.text_before_cursor();
+                let col = UnicodeWidthStr::width(&before[before.rfind('\n').map_or(0, |i| i + 1)..]);
+                let start = byte_pos + n + 1;
+                let end = self.front_buf[start..].find('\n').map_or(self.front_buf.len(), |n| start + n);
+                self.move_to_column(start, end, col);
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Moves to the last character of the line between the byte offsets
+    /// that starts at or before the column.
+    fn move_to_column(&mut self, start: usize, end: usize, col: usize) {
+        let mut pos = start;
+        let mut width = 0;
+        for g in UnicodeSegmentation::graphemes(&self.front_buf[start..end], true) {
+            width += UnicodeWidthStr::width(g);
+            if width > col {
+                break;
+            }
+            pos += g.len();
+        }
+        self.move_to_byte_pos(pos);
+    }
+
+    /// Returns the byte offset of the bracket matching the one at the byte
+    /// offset, ignoring brackets within quotes.
+    pub fn matching_bracket(&self, byte_pos: usize) -> Option<usize> {
+        let brackets = unquoted_brackets(&self.front_buf);
+        brackets.iter().position(|&(i, _)| i == byte_pos)
+            .and_then(|idx| match_bracket(&brackets, idx))
+    }
+
+    /// Moves to the bracket matching the one under the cursor, or the next
+    /// one after it, like `%` in vi.
+    pub fn move_to_matching_bracket(&mut self) -> bool {
+        let brackets = unquoted_brackets(&self.front_buf);
+        let byte_pos = self.byte_pos();
+        match brackets.iter().position(|&(i, _)| i >= byte_pos).and_then(|idx| match_bracket(&brackets, idx)) {
+            Some(pos) => {
+                self.move_to_byte_pos(pos);
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Moves past the brackets opened by the first opening bracket at or
+    /// after the cursor, like `C-M-f` in emacs.
+    pub fn move_over_brackets_forward(&mut self) -> bool {
+        let brackets = unquoted_brackets(&self.front_buf);
+        let byte_pos = self.byte_pos();
+        match brackets.iter().position(|&(i, c)| i >= byte_pos && is_opening_bracket(c))
+            .and_then(|idx| match_bracket(&brackets, idx)) {
+            Some(pos) => {
+                self.move_to_byte_pos(pos + 1);
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Moves back to the start of the brackets closed by the last closing
+    /// bracket before the cursor, like `C-M-b` in emacs.
+    pub fn move_over_brackets_backward(&mut self) -> bool {
+        let brackets = unquoted_brackets(&self.front_buf);
+        let byte_pos = self.byte_pos();
+        match brackets.iter().rposition(|&(i, c)| i < byte_pos && !is_opening_bracket(c))
+            .and_then(|idx| match_bracket(&brackets, idx)) {
+            Some(pos) => {
+                self.move_to_byte_pos(pos);
+                true
+            },
+            None => false
+        }
+    }
+
     pub fn start_delete(&mut self) -> DeleteContext {
         DeleteContext::new(self)
     }
 
-    pub fn get_line(&self, prompt: &str, clear: bool) -> Vec<u8> {
+    /// Replaces the text in the byte range, leaving the cursor after the new text.
+    pub fn replace_range(&mut self, start: usize, end: usize, s: &str) {
+        self.front_buf.replace_range(start..end, s);
+        self.move_to_byte_pos(start + s.len());
+    }
+
+    pub fn text_before_cursor(&self) -> &str {
+        &self.front_buf[..self.pos.byte_pos]
+    }
+
+    pub fn text_after_cursor(&self) -> &str {
+        &self.front_buf[self.pos.byte_pos..]
+    }
+
+    /// Moves the cursor to the character starting at (or containing) the byte offset.
+    pub fn move_to_byte_pos(&mut self, byte_pos: usize) {
+        self.move_start();
+        while self.pos.byte_pos < byte_pos && self.move_right() {}
+    }
+
+    /// Renders the lines of the text below each other, starting over on
+    /// the first row they were last drawn on, and records the rows they
+    /// take up now. Lines longer than the terminal wrap onto the rows
+    /// below. The cursor is left in the text.
+    pub fn get_line(&self, deco: &Decorations, rows: &mut Rows, clear: bool) -> Vec<u8> {
+        let width = deco.width.max(1);
         let mut line = Builder::new();
         if clear {
             line.clear_screen();
+        } else {
+            line.cursor_up(rows.cursor);
         }
         line.carriage_return();
-        line.append(prompt);
-        line.append(&self.front_buf);
-        line.erase_to_right();
-        line.set_cursor_pos(prompt.len() + self.char_pos());
+        line.append(&deco.prompt.text);
+        let lines: Vec<&str> = self.front_buf.split('\n').collect();
+        // where the text of each line starts
+        let mut starts = Vec::with_capacity(lines.len());
+        let mut pos = skip(width, 0, deco.prompt.width);
+        let mut end = pos;
+        let mut offset = 0;
+        for (i, text) in lines.iter().enumerate() {
+            if i > 0 {
+                line.append("\r\n");
+                line.append(&deco.prompt.continuation);
+                pos = skip(width, pos.0 + 1, deco.prompt.continuation_width);
+            }
+            starts.push(pos);
+            line.append(&paint(text, &spans_within(deco.spans, offset, text.len())));
+            pos = advance(width, pos, text);
+            end = wrap(width, pos);
+            offset += text.len() + 1;
+            if i + 1 < lines.len() {
+                // erasing at the end of a full row would erase its last column
+                if pos.1 < width {
+                    line.erase_to_right();
+                }
+            } else {
+                if let Some(hint) = deco.hint {
+                    let hint = hint.replace(char::is_control, " ");
+                    line.dim_color();
+                    line.append(&hint);
+                    line.reset_color();
+                    pos = advance(width, pos, &hint);
+                }
+                if pos.1 >= width {
+                    // move on from the end of the full row
+                    line.append("\r\n");
+                    pos = wrap(width, pos);
+                }
+                // rows left over from the last time are erased along with the last one
+                if rows.count + rows.below > pos.0 + 1 || !deco.below.is_empty() {
+                    line.erase_below();
+                } else {
+                    line.erase_to_right();
+                }
+            }
+            if let (0, Some(col)) = (i, deco.right) {
+                line.set_cursor_pos(col);
+                line.append(&deco.prompt.right);
+            }
+        }
+        let count = pos.0 + 1;
+        let mut below = 0;
+        for row in deco.below {
+            line.append("\r\n");
+            line.append(row);
+            below += display_width(row).saturating_sub(1) / width + 1;
+        }
+
+        let before = self.text_before_cursor();
+        let start = before.rfind('\n').map_or(0, |i| i + 1);
+        let mut cursor = advance(width, starts[before.matches('\n').count()], &before[start..]);
+        // the cursor goes where the next character is drawn
+        let next = UnicodeSegmentation::graphemes(self.text_after_cursor(), true).next()
+            .filter(|g| *g != "\n")
+            .map_or(1, |g| UnicodeWidthStr::width(g).max(1));
+        if cursor.1 + next > width && cursor.1 > 0 {
+            cursor = (cursor.0 + 1, 0);
+        }
+        line.cursor_up(count - 1 + below - cursor.0);
+        line.set_cursor_pos(cursor.1);
+        *rows = Rows { count: count, below: below, cursor: cursor.0, end_row: end.0, end: end.1 };
         line.build()
     }
 
+    /// Renders the prompt of an incremental history search, followed by the
+    /// matched entry with the cursor on the match, or the current line if
+    /// nothing matched yet.
+    pub fn get_search_line(&self, query: &str, dir: SearchDirection, failed: bool,
+                           matched: Option<(&str, usize)>, clear: bool) -> Vec<u8> {
+        let prompt = format!("({}{})`{}': ",
+                             if failed { "failed " } else { "" },
+                             match dir {
+                                 SearchDirection::Reverse => "reverse-i-search",
+                                 SearchDirection::Forward => "i-search"
+                             },
+                             query);
+        let width = UnicodeWidthStr::width(prompt.as_str());
+        match matched {
+            Some((text, offset)) => {
+                render_line(&prompt, width, text, UnicodeWidthStr::width(&text[..offset]), clear)
+            },
+            None => render_line(&prompt, width, &self.front_buf, self.char_pos(), clear)
+        }
+    }
+
     pub fn to_string(self) -> String {
         self.front_buf
     }
@@ -418,6 +643,154 @@ impl Buffer {
     pub fn is_empty(&self) -> bool {
         self.front_buf.is_empty()
     }
+
+    pub fn is_cursor_at_end(&self) -> bool {
+        self.pos.byte_pos == self.front_buf.len()
+    }
+}
+
+/// What is drawn along with the text of a buffer.
+pub struct Decorations<'a> {
+    pub prompt: &'a Prompt,
+    /// The styled parts of the text.
+    pub spans: &'a [Span],
+    /// Drawn dimmed after the text.
+    pub hint: Option<&'a str>,
+    /// The column the right prompt starts at, if it fits.
+    pub right: Option<usize>,
+    /// Rows drawn below the text, like completions or messages.
+    pub below: &'a [String],
+    /// The number of columns of the terminal.
+    pub width: usize
+}
+
+/// The rows of the terminal taken up by the drawn text.
+#[derive(Clone, Copy, Default)]
+pub struct Rows {
+    /// The number of rows of the text.
+    pub count: usize,
+    /// The number of rows drawn below the text.
+    pub below: usize,
+    /// The row of the cursor, counting from the first row of the text.
+    pub cursor: usize,
+    /// The row and column after the end of the text, leaving out the hint.
+    pub end_row: usize,
+    pub end: usize
+}
+
+/// Returns the row and column after the text drawn from the position,
+/// wrapping characters that do not fit onto the next row. After a full
+/// row the column is the width, as the terminal only wraps when the
+/// next character is drawn.
+fn advance(width: usize, (mut row, mut col): (usize, usize), text: &str) -> (usize, usize) {
+    for g in UnicodeSegmentation::graphemes(text, true) {
+        let w = UnicodeWidthStr::width(g);
+        if col + w > width && col > 0 {
+            row += 1;
+            col = 0;
+        }
+        col += w;
+    }
+    (row, col)
+}
+
+/// Returns the position after columns drawn from the start of the row.
+fn skip(width: usize, row: usize, cols: usize) -> (usize, usize) {
+    match cols {
+        0 => (row, 0),
+        _ => (row + (cols - 1) / width, (cols - 1) % width + 1)
+    }
+}
+
+/// Moves a position at the end of a full row to the start of the next one.
+fn wrap(width: usize, (row, col): (usize, usize)) -> (usize, usize) {
+    if col >= width {
+        (row + 1, 0)
+    } else {
+        (row, col)
+    }
+}
+
+/// Returns the parts of the spans within a line of the text, relative to it.
+fn spans_within(spans: &[Span], start: usize, len: usize) -> Vec<Span> {
+    spans.iter()
+        .filter(|span| span.range.start < start + len && span.range.end > start)
+        .map(|span| {
+            let range = span.range.start.max(start) - start..span.range.end.min(start + len) - start;
+            Span::new(range, span.style)
+        })
+        .collect()
+}
+
+pub fn render_line(prompt: &str, prompt_width: usize, text: &str, cursor: usize, clear: bool) -> Vec<u8> {
+    let mut line = Builder::new();
+    if clear {
+        line.clear_screen();
+    }
+    line.carriage_return();
+    line.append(prompt);
+    line.append(text);
+    line.erase_to_right();
+    line.set_cursor_pos(prompt_width + cursor);
+    line.build()
+}
+
+/// Returns the brackets of the line with their byte offsets, leaving out
+/// those within quotes.
+fn unquoted_brackets(line: &str) -> Vec<(usize, char)> {
+    let mut brackets = Vec::new();
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            _ if escaped => escaped = false,
+            (Some('\''), '\'') => quote = None,
+            (Some('\''), _) => {},
+            (_, '\\') => escaped = true,
+            (Some(q), _) if c == q => quote = None,
+            (Some(_), _) => {},
+            (None, '\'') | (None, '"') => quote = Some(c),
+            (None, '(') | (None, ')') | (None, '[') | (None, ']') | (None, '{') | (None, '}') => {
+                brackets.push((i, c))
+            },
+            (None, _) => {}
+        }
+    }
+    brackets
+}
+
+fn is_opening_bracket(c: char) -> bool {
+    c == '(' || c == '[' || c == '{'
+}
+
+/// Returns the byte offset of the bracket matching the one at `idx`.
+fn match_bracket(brackets: &[(usize, char)], idx: usize) -> Option<usize> {
+    let c = brackets[idx].1;
+    let (other, forward) = match c {
+        '(' => (')', true),
+        '[' => (']', true),
+        '{' => ('}', true),
+        ')' => ('(', false),
+        ']' => ('[', false),
+        _ => ('{', false)
+    };
+    let candidates: Box<dyn Iterator<Item=&(usize, char)>> = if forward {
+        Box::new(brackets[idx..].iter())
+    } else {
+        Box::new(brackets[..idx + 1].iter().rev())
+    };
+    let mut depth = 0;
+    for &(i, b) in candidates {
+        if b == c {
+            depth += 1;
+        } else if b == other {
+            depth -= 1;
+            if depth == 0 {
+                return Some(i);
+            }
+        }
+    }
+    None
 }
 
 #[must_use]
@@ -446,6 +819,21 @@ impl<'a> DeleteContext<'a> {
     pub fn delete(mut self) {
         self.buf.delete_to_pos(self.start_pos)
     }
+
+    /// Deletes like `delete`, including the character at the later of the
+    /// two positions.
+    pub fn delete_inclusive(mut self) {
+        if self.buf.pos >= self.start_pos {
+            self.buf.move_right();
+        } else {
+            let pos = self.buf.pos;
+            self.buf.move_to_pos(self.start_pos);
+            self.buf.move_right();
+            self.start_pos = self.buf.pos;
+            self.buf.move_to_pos(pos);
+        }
+        self.buf.delete_to_pos(self.start_pos)
+    }
 }
 
 impl<'a> Deref for DeleteContext<'a> {
@@ -981,3 +1369,203 @@ fn move_and_delete3() {
     }
     assert_eq!(buf.to_string(), "".to_string());
 }
+
+#[test]
+fn move_word_forward() {
+    let mut buf = Buffer::new();
+    buf.insert_chars_at_cursor("git  co-mmit");
+    buf.move_start();
+    assert!(buf.move_word_forward());
+    assert_eq!(buf.char_pos(), 3);
+    assert!(buf.move_word_forward());
+    assert_eq!(buf.char_pos(), 7);
+    assert!(buf.move_word_forward());
+    assert_eq!(buf.char_pos(), 12);
+    assert!(!buf.move_word_forward());
+}
+
+#[cfg(test)]
+fn draw(buf: &Buffer, prompt: &Prompt, spans: &[Span], hint: Option<&str>, right: Option<usize>) -> String {
+    let deco = Decorations { prompt: prompt, spans: spans, hint: hint, right: right, below: &[], width: 80 };
+    String::from_utf8(buf.get_line(&deco, &mut Rows::default(), false)).unwrap()
+}
+
+#[test]
+fn hint_not_counted_in_cursor() {
+    let mut buf = Buffer::new();
+    buf.insert_chars_at_cursor("git c");
+    assert_eq!(draw(&buf, &Prompt::new("> ", None, ""), &[], Some("ommit"), None),
+               "\r> git c\x1b[2mommit\x1b[0m\x1b[0K\r\x1b[7C");
+}
+
+#[test]
+fn highlighting_not_counted_in_cursor() {
+    use style::Style;
+    let mut buf = Buffer::new();
+    buf.insert_chars_at_cursor("ab");
+    let spans = [Span::new(0..1, Style { underline: true, ..Style::new() })];
+    assert_eq!(draw(&buf, &Prompt::new("> ", None, ""), &spans, None, None),
+               "\r> \x1b[4ma\x1b[0mb\x1b[0K\r\x1b[4C");
+}
+
+#[test]
+fn matching_brackets() {
+    let mut buf = Buffer::new();
+    buf.insert_chars_at_cursor("f(a[1], \"(\", ')', \\() {x}");
+    assert_eq!(buf.matching_bracket(1), Some(20));
+    assert_eq!(buf.matching_bracket(20), Some(1));
+    assert_eq!(buf.matching_bracket(3), Some(5));
+    assert_eq!(buf.matching_bracket(22), Some(24));
+    // quoted and escaped brackets are skipped
+    assert_eq!(buf.matching_bracket(9), None);
+    assert_eq!(buf.matching_bracket(14), None);
+    assert_eq!(buf.matching_bracket(19), None);
+    assert_eq!(buf.matching_bracket(0), None);
+
+    buf.move_start();
+    assert!(buf.move_to_matching_bracket());
+    assert_eq!(buf.byte_pos(), 20);
+    assert!(buf.move_to_matching_bracket());
+    assert_eq!(buf.byte_pos(), 1);
+    buf.move_end();
+    assert!(!buf.move_to_matching_bracket());
+}
+
+#[test]
+fn move_over_brackets() {
+    let mut buf = Buffer::new();
+    buf.insert_chars_at_cursor("a (b [c]) \")\" {d}");
+    assert!(buf.move_over_brackets_backward());
+    assert_eq!(buf.byte_pos(), 14);
+    assert!(buf.move_over_brackets_backward());
+    assert_eq!(buf.byte_pos(), 2);
+    assert!(!buf.move_over_brackets_backward());
+    assert!(buf.move_over_brackets_forward());
+    assert_eq!(buf.byte_pos(), 9);
+    assert!(buf.move_over_brackets_forward());
+    assert_eq!(buf.byte_pos(), 17);
+    assert!(!buf.move_over_brackets_forward());
+    buf.move_to_byte_pos(8);
+    assert!(buf.move_over_brackets_backward());
+    assert_eq!(buf.byte_pos(), 5);
+}
+
+#[test]
+fn delete_to_matching_bracket() {
+    let mut buf = Buffer::new();
+    buf.insert_chars_at_cursor("a (b c) d");
+    buf.move_to_byte_pos(6);
+    {
+        let mut dc = buf.start_delete();
+        dc.move_to_matching_bracket();
+        dc.delete_inclusive();
+    }
+    assert_eq!(buf.as_str(), "a  d");
+    assert_eq!(buf.byte_pos(), 2);
+}
+
+#[test]
+fn right_prompt_drawn() {
+    let mut buf = Buffer::new();
+    buf.insert_chars_at_cursor("ab");
+    let prompt = Prompt::new("> ", Some("[1]"), "");
+    assert_eq!(draw(&buf, &prompt, &[], None, Some(16)),
+               "\r> ab\x1b[0K\r\x1b[16C[1]\r\x1b[4C");
+}
+
+#[test]
+fn continuation_lines() {
+    use style::Style;
+    let mut buf = Buffer::new();
+    buf.insert_chars_at_cursor("(a\nb)");
+    buf.move_to_byte_pos(1);
+    let prompt = Prompt::new("> ", None, ".. ");
+    let spans = [Span::new(1..4, Style { bold: true, ..Style::new() })];
+    let below = ["error".to_owned()];
+    let deco = Decorations { prompt: &prompt, spans: &spans, hint: None, right: Some(20), below: &below, width: 80 };
+    let mut rows = Rows::default();
+    assert_eq!(String::from_utf8(buf.get_line(&deco, &mut rows, false)).unwrap(),
+               "\r> (\x1b[1ma\x1b[0m\x1b[0K\r\x1b[20C\r\n.. \x1b[1mb\x1b[0m)\x1b[0J\r\nerror\x1b[2A\r\x1b[3C");
+    assert_eq!((rows.count, rows.below, rows.cursor, rows.end), (2, 1, 0, 5));
+
+    // drawn again from the first row, erasing the rows left over
+    buf.move_end();
+    let deco = Decorations { prompt: &prompt, spans: &[], hint: None, right: None, below: &[], width: 80 };
+    assert_eq!(String::from_utf8(buf.get_line(&deco, &mut rows, false)).unwrap(),
+               "\r> (a\x1b[0K\r\n.. b)\x1b[0J\r\x1b[5C");
+    assert_eq!((rows.count, rows.below, rows.cursor), (2, 0, 1));
+    assert_eq!(String::from_utf8(buf.get_line(&deco, &mut rows, false)).unwrap(),
+               "\x1b[1A\r> (a\x1b[0K\r\n.. b)\x1b[0K\r\x1b[5C");
+}
+
+#[test]
+fn move_between_lines() {
+    let mut buf = Buffer::new();
+    buf.insert_chars_at_cursor("abcd\n日本\n\nxy");
+    assert!(!buf.move_line_down());
+    assert!(buf.move_line_up());
+    assert_eq!(buf.byte_pos(), 12);
+    assert!(buf.move_line_up());
+    assert_eq!(buf.byte_pos(), 5);
+    assert!(buf.move_line_up());
+    assert_eq!(buf.byte_pos(), 0);
+    assert!(!buf.move_line_up());
+    buf.move_to_byte_pos(3);
+    // columns within a wide character move before it
+    assert!(buf.move_line_down());
+    assert_eq!(buf.byte_pos(), 8);
+    assert!(buf.move_line_down());
+    assert_eq!(buf.byte_pos(), 12);
+    assert!(buf.move_line_down());
+    assert_eq!(buf.byte_pos(), 13);
+}
+
+#[test]
+fn wrapped_lines() {
+    let prompt = Prompt::new("> ", None, ".. ");
+    let deco = Decorations { prompt: &prompt, spans: &[], hint: None, right: None, below: &[], width: 10 };
+    let mut rows = Rows::default();
+    let mut buf = Buffer::new();
+    buf.insert_chars_at_cursor("abcdefghijkl");
+    assert_eq!(buf.get_line(&deco, &mut rows, false), b"\r> abcdefghijkl\x1b[0K\r\x1b[4C");
+    assert_eq!((rows.count, rows.cursor, rows.end_row, rows.end), (2, 1, 1, 4));
+    buf.move_start();
+    assert_eq!(buf.get_line(&deco, &mut rows, false),
+               b"\x1b[1A\r> abcdefghijkl\x1b[0K\x1b[1A\r\x1b[2C");
+    assert_eq!(rows.cursor, 0);
+    // a full row moves the cursor on to the next one
+    buf.move_end();
+    buf.delete_char_left_of_cursor();
+    buf.delete_char_left_of_cursor();
+    buf.delete_char_left_of_cursor();
+    buf.delete_char_left_of_cursor();
+    assert_eq!(buf.get_line(&deco, &mut rows, false), b"\r> abcdefgh\r\n\x1b[0K\r");
+    assert_eq!((rows.count, rows.cursor, rows.end_row, rows.end), (2, 1, 1, 0));
+    // rows no longer taken up are erased
+    buf.drain();
+    buf.insert_chars_at_cursor("ab\ncdefghijkl");
+    assert_eq!(buf.get_line(&deco, &mut rows, false),
+               b"\x1b[1A\r> ab\x1b[0K\r\n.. cdefghijkl\x1b[0K\r\x1b[3C");
+    assert_eq!((rows.count, rows.cursor), (3, 2));
+    buf.move_to_byte_pos(1);
+    buf.delete_char_right_of_cursor();
+    buf.delete_char_right_of_cursor();
+    assert_eq!(buf.get_line(&deco, &mut rows, false), b"\x1b[2A\r> acdefghijkl\x1b[0J\x1b[1A\r\x1b[3C");
+    assert_eq!((rows.count, rows.cursor), (2, 0));
+}
+
+#[test]
+fn wrapped_wide_chars() {
+    let prompt = Prompt::new("> ", None, "");
+    let deco = Decorations { prompt: &prompt, spans: &[], hint: None, right: None, below: &[], width: 10 };
+    let mut rows = Rows::default();
+    let mut buf = Buffer::new();
+    // a wide character that does not fit leaves the last column empty
+    buf.insert_chars_at_cursor("abcdefg日本");
+    assert_eq!(buf.get_line(&deco, &mut rows, false), b"\r> abcdefg\xe6\x97\xa5\xe6\x9c\xac\x1b[0K\r\x1b[4C");
+    buf.move_left();
+    buf.move_left();
+    assert_eq!(buf.get_line(&deco, &mut rows, false),
+               b"\x1b[1A\r> abcdefg\xe6\x97\xa5\xe6\x9c\xac\x1b[0K\r");
+    assert_eq!((rows.count, rows.cursor), (2, 1));
+}
@@ -24,8 +24,27 @@ impl Builder {
         self.seq.extend(b"\x1b[0K");
     }
 
+    pub fn erase_below(&mut self) {
+        self.seq.extend(b"\x1b[0J");
+    }
+
+    pub fn cursor_up(&mut self, rows: usize) {
+        if rows > 0 {
+            self.seq.extend(&format!("\x1b[{}A", rows).into_bytes());
+        }
+    }
+
+    pub fn cursor_down(&mut self, rows: usize) {
+        if rows > 0 {
+            self.seq.extend(&format!("\x1b[{}B", rows).into_bytes());
+        }
+    }
+
     pub fn set_cursor_pos(&mut self, pos: usize) {
-        self.seq.extend(&format!("\r\x1b[{}C", pos).into_bytes());
+        self.carriage_return();
+        if pos > 0 {
+            self.seq.extend(&format!("\x1b[{}C", pos).into_bytes());
+        }
     }
 
     pub fn clear_screen(&mut self) {
@@ -36,6 +55,10 @@ impl Builder {
         self.seq.extend(b"\x1b[0m")
     }
 
+    pub fn dim_color(&mut self) {
+        self.seq.extend(b"\x1b[2m")
+    }
+
     pub fn invert_color(&mut self) {
         self.seq.extend(b"\x1b[7m")
     }
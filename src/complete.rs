@@ -0,0 +1,482 @@
+use std::env;
+use std::fs;
+use std::ops::Range;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+use style::{Color, Style};
+
+/// A possible completion, listed as `display` and inserted as `replacement`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Candidate {
+    pub display: String,
+    pub replacement: String,
+    /// Shown next to the candidate when completions are listed.
+    pub description: Option<String>,
+    pub style: Style,
+    /// The heading the candidate is listed under.
+    pub group: Option<String>
+}
+
+impl Candidate {
+    /// Returns a plain candidate displayed as it is inserted.
+    pub fn new<S: Into<String>>(replacement: S) -> Candidate {
+        let replacement = replacement.into();
+        Candidate {
+            display: replacement.clone(),
+            replacement: replacement,
+            description: None,
+            style: Style::new(),
+            group: None
+        }
+    }
+}
+
+impl From<String> for Candidate {
+    fn from(s: String) -> Candidate {
+        Candidate::new(s)
+    }
+}
+
+impl<'a> From<&'a str> for Candidate {
+    fn from(s: &'a str) -> Candidate {
+        Candidate::new(s)
+    }
+}
+
+/// The candidates for completing the text in `span`.
+pub struct Completion {
+    /// The byte range of the line replaced by a candidate.
+    pub span: Range<usize>,
+    pub candidates: Vec<Candidate>
+}
+
+impl Completion {
+    pub fn new<C: Into<Candidate>>(span: Range<usize>, candidates: Vec<C>) -> Completion {
+        Completion { span: span, candidates: candidates.into_iter().map(Into::into).collect() }
+    }
+}
+
+/// A source of completions, asked for candidates when `Tab` is pressed.
+pub trait Completer {
+    /// Returns the candidates for the line with the cursor at the byte
+    /// offset `pos`.
+    fn complete(&self, line: &str, pos: usize) -> Completion;
+}
+
+impl<F> Completer for F where F: Fn(&str, usize) -> Completion {
+    fn complete(&self, line: &str, pos: usize) -> Completion {
+        self(line, pos)
+    }
+}
+
+/// Characters escaped with a backslash in completed file names.
+const SPECIAL_CHARS: &str = " \t\n\\'\"|&;()<>$`*?[]{}#!";
+
+/// Completes file names of relative and absolute paths, with `~` standing
+/// for the home directory.
+///
+/// Directories are completed with a trailing `/`. Special characters are
+/// escaped with backslashes, unless the path is within quotes.
+#[derive(Default)]
+pub struct FilenameCompleter {
+    /// Only complete files with one of these extensions.
+    pub extensions: Option<Vec<String>>,
+    /// Only complete executable files.
+    pub executables_only: bool
+}
+
+impl FilenameCompleter {
+    pub fn new() -> FilenameCompleter {
+        FilenameCompleter::default()
+    }
+
+    fn accepts(&self, path: &Path, metadata: &fs::Metadata) -> bool {
+        if metadata.is_dir() {
+            return true;
+        }
+        if self.executables_only && metadata.permissions().mode() & 0o111 == 0 {
+            return false;
+        }
+        match self.extensions {
+            Some(ref extensions) => {
+                let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+                extensions.iter().any(|e| e == ext)
+            },
+            None => true
+        }
+    }
+}
+
+impl Completer for FilenameCompleter {
+    fn complete(&self, line: &str, pos: usize) -> Completion {
+        let (start, quote) = word_start(&line[..pos]);
+        let path = unescape(&line[start..pos]);
+        let (dir, prefix) = match path.rfind('/') {
+            Some(i) => path.split_at(i + 1),
+            None => ("", path.as_str())
+        };
+        let home = env::var_os("HOME").map(PathBuf::from);
+        let read_dir = match dir {
+            "" => PathBuf::from("."),
+            dir => expand_home(dir, home.as_deref())
+        };
+        let mut candidates = Vec::new();
+        if let Ok(entries) = fs::read_dir(&read_dir) {
+            for entry in entries.filter_map(|e| e.ok()) {
+                let name = match entry.file_name().into_string() {
+                    Ok(name) => name,
+                    Err(_) => continue
+                };
+                // hidden files are only completed when asked for
+                if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+                    continue;
+                }
+                // follow symlinks to tell directories apart
+                let metadata = match fs::metadata(entry.path()) {
+                    Ok(metadata) => metadata,
+                    Err(_) => continue
+                };
+                if !self.accepts(&entry.path(), &metadata) {
+                    continue;
+                }
+                let mut display = name.clone();
+                let mut style = Style::new();
+                if metadata.is_dir() {
+                    display.push('/');
+                    style = Style { bold: true, ..Style::fg(Color::Blue) };
+                }
+                candidates.push(Candidate {
+                    replacement: escape(&format!("{}{}", dir, display), quote),
+                    display: display,
+                    style: style,
+                    ..Candidate::new("")
+                });
+            }
+        }
+        candidates.sort_by(|a, b| a.display.cmp(&b.display));
+        // keep an opening quote in front of the completed path
+        let start = if quote.is_some() { start + 1 } else { start };
+        Completion::new(start..pos, candidates)
+    }
+}
+
+/// Finds the start of the word before the cursor, which is separated by
+/// whitespace that is neither quoted nor escaped. Also returns the quote
+/// the word starts with, if it is still open.
+fn word_start(line: &str) -> (usize, Option<char>) {
+    let mut start = 0;
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            _ if escaped => escaped = false,
+            (Some('\''), '\'') => quote = None,
+            (Some('\''), _) => {},
+            (_, '\\') => escaped = true,
+            (Some(q), _) if c == q => quote = None,
+            (Some(_), _) => {},
+            (None, '\'') | (None, '"') => quote = Some(c),
+            (None, _) if c.is_whitespace() => start = i + c.len_utf8(),
+            (None, _) => {}
+        }
+    }
+    // only quotes at the start of the word are kept
+    match quote {
+        Some(q) if line[start..].starts_with(q) => (start, quote),
+        _ => (start, None)
+    }
+}
+
+/// Removes quotes and escaping backslashes from a word.
+fn unescape(word: &str) -> String {
+    let mut s = String::with_capacity(word.len());
+    let mut quote = None;
+    let mut chars = word.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), _) if c == q => quote = None,
+            (Some('\''), _) => s.push(c),
+            (_, '\\') => s.extend(chars.next()),
+            (Some(_), _) => s.push(c),
+            (None, '\'') | (None, '"') => quote = Some(c),
+            (None, _) => s.push(c)
+        }
+    }
+    s
+}
+
+/// Escapes a path for the line, leaving a leading `~` alone. Within quotes
+/// only the quote itself needs escaping.
+fn escape(path: &str, quote: Option<char>) -> String {
+    let mut s = String::with_capacity(path.len());
+    for (i, c) in path.char_indices() {
+        let special = match quote {
+            Some('\'') => false,
+            Some(q) => c == q || c == '\\' || c == '$' || c == '`',
+            None => SPECIAL_CHARS.contains(c) && !(i == 0 && c == '~')
+        };
+        if special {
+            s.push('\\');
+        }
+        s.push(c);
+    }
+    s
+}
+
+/// Replaces a leading `~` with the home directory.
+fn expand_home(path: &str, home: Option<&Path>) -> PathBuf {
+    match (path.strip_prefix('~'), home) {
+        (Some(rest), Some(home)) if rest.is_empty() || rest.starts_with('/') => {
+            home.join(rest.trim_start_matches('/'))
+        },
+        _ => PathBuf::from(path)
+    }
+}
+
+/// Returns the longest prefix shared by all candidates.
+pub fn common_prefix<S: AsRef<str>>(candidates: &[S]) -> &str {
+    let first = match candidates.first() {
+        Some(first) => first.as_ref(),
+        None => return ""
+    };
+    let mut len = first.len();
+    for candidate in &candidates[1..] {
+        len = first[..len].char_indices()
+            .zip(candidate.as_ref().chars())
+            .find(|&((_, a), b)| a != b)
+            .map_or(len.min(candidate.as_ref().len()), |((i, _), _)| i);
+    }
+    // the length may fall into a character when a candidate is shorter
+    while !first.is_char_boundary(len) {
+        len -= 1;
+    }
+    &first[..len]
+}
+
+/// Returns how many columns of which width fit the candidates into the width.
+pub fn column_layout<S: AsRef<str>>(candidates: &[S], width: usize) -> (usize, usize) {
+    let max = candidates.iter().map(|c| UnicodeWidthStr::width(c.as_ref())).max().unwrap_or(0);
+    let col_width = max + 2;
+    let cols = if col_width > width { 1 } else { width / col_width };
+    (cols, col_width)
+}
+
+#[cfg(test)]
+fn format_columns<S: AsRef<str>>(candidates: &[S], width: usize) -> Vec<String> {
+    let candidates: Vec<Candidate> = candidates.iter().map(|c| Candidate::new(c.as_ref())).collect();
+    format_candidates(&candidates, width, None).0
+}
+
+/// Orders the candidates by group, in the order the groups first appear.
+pub fn group_candidates(candidates: &mut [Candidate]) {
+    let mut groups: Vec<Option<String>> = Vec::new();
+    for candidate in candidates.iter() {
+        if !groups.contains(&candidate.group) {
+            groups.push(candidate.group.clone());
+        }
+    }
+    candidates.sort_by_key(|c| groups.iter().position(|g| *g == c.group));
+}
+
+/// Returns the number of columns the candidates are laid out in. Candidates
+/// with descriptions get a row each.
+pub fn candidate_columns(candidates: &[Candidate], width: usize) -> usize {
+    if candidates.iter().any(|c| c.description.is_some()) {
+        1
+    } else {
+        let displays: Vec<&str> = candidates.iter().map(|c| c.display.as_str()).collect();
+        column_layout(&displays, width).0
+    }
+}
+
+/// Lays out grouped candidates in rows fitting the width, with the
+/// selected one inverted, and returns the rows and the row of the selected
+/// candidate.
+///
+/// Each group is listed below its heading. Descriptions are aligned in a
+/// second column and cut off at the width.
+pub fn format_candidates(candidates: &[Candidate], width: usize, selected: Option<usize>) -> (Vec<String>, Option<usize>) {
+    let displays: Vec<&str> = candidates.iter().map(|c| c.display.as_str()).collect();
+    let (_, col_width) = column_layout(&displays, width);
+    let cols = candidate_columns(candidates, width);
+    let mut rows = Vec::new();
+    let mut selected_row = None;
+    let mut start = 0;
+    while start < candidates.len() {
+        let group = &candidates[start].group;
+        let end = candidates[start..].iter().position(|c| c.group != *group)
+            .map_or(candidates.len(), |n| start + n);
+        if let Some(ref heading) = *group {
+            rows.push(Style { bold: true, ..Style::new() }.paint(heading));
+        }
+        for (r, row) in candidates[start..end].chunks(cols).enumerate() {
+            let mut line = String::new();
+            for (i, candidate) in row.iter().enumerate() {
+                let idx = start + r * cols + i;
+                let display_width = UnicodeWidthStr::width(candidate.display.as_str());
+                if selected == Some(idx) {
+                    selected_row = Some(rows.len());
+                    line.push_str(&Style { inverted: true, ..Style::new() }.paint(&candidate.display));
+                } else {
+                    line.push_str(&candidate.style.paint(&candidate.display));
+                }
+                if let Some(ref description) = candidate.description {
+                    // leave room for the padding and the parentheses
+                    let room = width.saturating_sub(col_width + 2);
+                    if room > 0 {
+                        for _ in display_width..col_width {
+                            line.push(' ');
+                        }
+                        line.push('(');
+                        line.push_str(&truncate(description, room));
+                        line.push(')');
+                    }
+                } else if i + 1 < row.len() {
+                    for _ in display_width..col_width {
+                        line.push(' ');
+                    }
+                }
+            }
+            rows.push(line);
+        }
+        start = end;
+    }
+    (rows, selected_row)
+}
+
+/// Cuts off text wider than `width`, ending it with an ellipsis.
+pub fn truncate(text: &str, width: usize) -> String {
+    if UnicodeWidthStr::width(text) <= width {
+        return text.to_owned();
+    }
+    let mut s = String::new();
+    let mut used = 0;
+    for c in text.chars() {
+        let w = UnicodeWidthChar::width(c).unwrap_or(0);
+        if used + w + 1 > width {
+            break;
+        }
+        used += w;
+        s.push(c);
+    }
+    s.push('…');
+    s
+}
+
+#[test]
+fn columns_fit_width() {
+    let candidates = ["a", "bbb", "cc", "d", "eeee"];
+    assert_eq!(format_columns(&candidates, 20), vec!["a     bbb   cc", "d     eeee"]);
+    assert_eq!(format_columns(&candidates, 12), vec!["a     bbb", "cc    d", "eeee"]);
+    assert_eq!(format_columns(&candidates, 3), vec!["a", "bbb", "cc", "d", "eeee"]);
+    assert_eq!(format_columns(&["日本", "x"], 12), vec!["日本  x"]);
+    let candidates: Vec<Candidate> = candidates.iter().map(|&c| Candidate::new(c)).collect();
+    assert_eq!(format_candidates(&candidates, 12, Some(3)),
+               (vec!["a     bbb".to_owned(), "cc    \x1b[7md\x1b[0m".to_owned(), "eeee".to_owned()], Some(1)));
+}
+
+#[test]
+fn described_candidates() {
+    let candidate = |display: &str, description: Option<&str>, group: Option<&str>| Candidate {
+        description: description.map(str::to_owned),
+        group: group.map(str::to_owned),
+        ..Candidate::new(display)
+    };
+    let mut candidates = vec![
+        candidate("add", Some("Add file contents"), Some("commands")),
+        candidate("-v", None, Some("options")),
+        candidate("commit", Some("Record changes"), Some("commands")),
+    ];
+    group_candidates(&mut candidates);
+    assert_eq!(candidates[1].display, "commit");
+    let (rows, selected) = format_candidates(&candidates, 24, Some(2));
+    assert_eq!(rows, vec![
+        "\x1b[1mcommands\x1b[0m",
+        "add     (Add file cont…)",
+        "commit  (Record changes)",
+        "\x1b[1moptions\x1b[0m",
+        "\x1b[7m-v\x1b[0m",
+    ]);
+    assert_eq!(selected, Some(4));
+    // descriptions are left out when there is no room for them
+    assert_eq!(format_candidates(&candidates[..1], 7, None).0, vec!["\x1b[1mcommands\x1b[0m", "add"]);
+    let styled = Candidate { style: Style::fg(Color::Green), ..Candidate::new("x") };
+    assert_eq!(format_candidates(&[styled], 10, None).0, vec!["\x1b[32mx\x1b[0m"]);
+}
+
+#[test]
+fn common_prefix_of_candidates() {
+    let empty: [&str; 0] = [];
+    assert_eq!(common_prefix(&empty), "");
+    assert_eq!(common_prefix(&["foo"]), "foo");
+    assert_eq!(common_prefix(&["foobar", "foobaz", "foo"]), "foo");
+    assert_eq!(common_prefix(&["foobar", "foobaz"]), "fooba");
+    assert_eq!(common_prefix(&["abc", "xyz"]), "");
+    assert_eq!(common_prefix(&["äöü", "äöx", "äö"]), "äö");
+    assert_eq!(common_prefix(&["aé", "a"]), "a");
+}
+
+#[test]
+fn filename_words() {
+    assert_eq!(word_start("cat foo"), (4, None));
+    assert_eq!(word_start("cat foo\\ b"), (4, None));
+    assert_eq!(word_start("cat \"foo b"), (4, Some('"')));
+    assert_eq!(word_start("cat 'foo b"), (4, Some('\'')));
+    assert_eq!(word_start("cat \"foo\" b"), (10, None));
+    assert_eq!(unescape("foo\\ bar"), "foo bar");
+    assert_eq!(unescape("\"foo b"), "foo b");
+    assert_eq!(unescape("'a\\b'c"), "a\\bc");
+    assert_eq!(escape("~/a b&c", None), "~/a\\ b\\&c");
+    assert_eq!(escape("a b\"c", Some('"')), "a b\\\"c");
+    assert_eq!(escape("a b\\c", Some('\'')), "a b\\c");
+    let home = Path::new("/home/user");
+    assert_eq!(expand_home("~/src/", Some(home)), PathBuf::from("/home/user/src/"));
+    assert_eq!(expand_home("~", Some(home)), PathBuf::from("/home/user"));
+    assert_eq!(expand_home("~other/", Some(home)), PathBuf::from("~other/"));
+    assert_eq!(expand_home("~/", None), PathBuf::from("~/"));
+}
+
+#[test]
+fn filename_completion() {
+    use std::fs::File;
+    let dir = env::temp_dir().join(format!("copperline-complete-{}", ::std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("fob")).unwrap();
+    for name in ["foo bar.txt", "foo.rs", "run.sh", ".hidden"].iter() {
+        File::create(dir.join(name)).unwrap();
+    }
+    fs::set_permissions(dir.join("run.sh"), fs::Permissions::from_mode(0o755)).unwrap();
+    let d = dir.to_str().unwrap();
+
+    let complete = |completer: &FilenameCompleter, line: &str| {
+        let completion = completer.complete(line, line.len());
+        let replacements = completion.candidates.into_iter().map(|c| c.replacement).collect::<Vec<_>>();
+        (completion.span, replacements)
+    };
+    let all = FilenameCompleter::new();
+    let line = format!("cat {}/fo", d);
+    assert_eq!(complete(&all, &line),
+               (4..line.len(), vec![format!("{}/fob/", d), format!("{}/foo\\ bar.txt", d), format!("{}/foo.rs", d)]));
+    let line = format!("cat {}/foo\\ ", d);
+    assert_eq!(complete(&all, &line).1, vec![format!("{}/foo\\ bar.txt", d)]);
+    let line = format!("cat \"{}/foo ", d);
+    assert_eq!(complete(&all, &line), (5..line.len(), vec![format!("{}/foo bar.txt", d)]));
+    let line = format!("cat {}/.", d);
+    assert_eq!(complete(&all, &line).1, vec![format!("{}/.hidden", d)]);
+
+    let rust = FilenameCompleter { extensions: Some(vec!["rs".to_owned()]), ..FilenameCompleter::new() };
+    let line = format!("{}/", d);
+    assert_eq!(complete(&rust, &line).1, vec![format!("{}/fob/", d), format!("{}/foo.rs", d)]);
+    let exec = FilenameCompleter { executables_only: true, ..FilenameCompleter::new() };
+    assert_eq!(complete(&exec, &line).1, vec![format!("{}/fob/", d), format!("{}/run.sh", d)]);
+    // candidates are listed by their name
+    let completion = all.complete(&line, line.len());
+    let displays: Vec<&str> = completion.candidates.iter().map(|c| c.display.as_str()).collect();
+    assert_eq!(displays, vec!["fob/", "foo bar.txt", "foo.rs", "run.sh"]);
+    assert!(completion.candidates[0].style.bold);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -1,11 +1,25 @@
 use encoding::types::EncodingRef;
 
+use std::ops::Range;
+use std::mem::swap;
 use std::u32;
 use error::Error;
-use history::{Cursor, History};
-use buffer::Buffer;
+use history::{Cursor, HistoryStore, SearchDirection, line_of};
+use expand::{expand, split_words};
+use fuzzy::Picker;
+use complete::{Candidate, Completer, candidate_columns, common_prefix, format_candidates, group_candidates};
+use hint::{Hinter, first_word};
+use highlight::{Highlighter, Span};
+use style::Style;
+use prompt::Prompt;
+use validate::{ValidationResult, Validator};
+use builder::Builder;
+use buffer::{Buffer, Decorations, Rows, render_line};
+use unicode_width::UnicodeWidthStr;
 use parser::{parse, ParseError, ParseSuccess};
 use instr;
+use enc;
+use Config;
 
 #[derive(Copy,Clone)]
 pub enum EditMode {
@@ -75,28 +89,144 @@ fn next_vi_mode(mode_state: ModeState) -> ModeState {
     }
 }
 
+/// State of an incremental history search.
+struct Search {
+    query: String,
+    dir: SearchDirection,
+    // index of the entry the search started at
+    start: usize,
+    // index of the matched entry and byte offset of the match within it
+    found: Option<(usize, usize)>,
+    failed: bool,
+}
+
 pub struct EditCtx<'a> {
     buf: Buffer,
+    history: &'a dyn HistoryStore,
     history_cursor: Cursor<'a>,
-    prompt: &'a str,
+    prompt: Prompt,
+    prompt_drawn: bool,
+    // whether the right prompt is drawn and is erased when the line is done
+    right_prompt_shown: bool,
+    transient_right_prompt: bool,
+    // the rows the line was last drawn on
+    rows: Rows,
     seq: Vec<u8>,
     enc: EncodingRef,
     mode_state: ModeState,
+    search: Option<Search>,
+    prefix_search: bool,
+    // prefix that history browsing started with, if prefix search applies
+    history_prefix: Option<String>,
+    history_expansion: bool,
+    picker: Option<Picker>,
+    picker_rows: usize,
+    // set when the picker or menu closes, so the rows they used get cleared
+    clear_below: bool,
+    // the query of a vi history search while it is typed
+    vi_search: Option<(String, SearchDirection)>,
+    // the last vi history search, repeated by `n` and `N`
+    last_vi_search: Option<(String, SearchDirection)>,
+    // numeric argument given with meta digits in emacs mode
+    arg: Option<u32>,
+    // the history entry and byte range of the word inserted by the last yank-last-arg
+    yanked_arg: Option<(usize, Range<usize>)>,
+    completer: Option<&'a dyn Completer>,
+    hinter: Option<&'a dyn Hinter>,
+    highlighter: Option<&'a dyn Highlighter>,
+    validator: Option<&'a dyn Validator>,
+    // the message of the validator about the line, shown below it
+    invalid: Option<String>,
+    match_brackets: bool,
+    // the suggestion drawn after the line
+    hint: Option<String>,
+    // set when the last instruction was a completion
+    completed: bool,
+    // completions being listed below the line
+    listing: Option<Listing>,
+    // completions being cycled through in place
+    menu: Option<Menu>,
+    menu_complete: bool,
+    query_items: usize,
+    // output to write before the line is drawn
+    pending: Vec<u8>,
+    width: usize,
+    height: usize,
 }
 
 impl<'a> EditCtx<'a> {
 
-    pub fn new(prompt: &'a str, history: &'a History, enc: EncodingRef, mode: EditMode) -> Self {
+    pub fn new(prompt: &'a str, history: &'a dyn HistoryStore, cfg: &Config) -> Self {
         EditCtx {
             buf: Buffer::new(),
+            history: history,
             history_cursor: Cursor::new(history),
-            prompt: prompt,
+            prompt: Prompt::new(prompt, cfg.right_prompt.as_deref(), &cfg.continuation_prompt),
+            prompt_drawn: false,
+            right_prompt_shown: false,
+            transient_right_prompt: cfg.transient_right_prompt,
+            rows: Rows::default(),
             seq: Vec::new(),
-            enc: enc,
-            mode_state: ModeState::new(mode),
+            enc: enc::to_encoding_ref(&cfg.encoding),
+            mode_state: ModeState::new(cfg.mode),
+            search: None,
+            prefix_search: cfg.history_prefix_search,
+            history_prefix: None,
+            history_expansion: cfg.history_expansion,
+            picker: None,
+            picker_rows: cfg.fuzzy_search_rows,
+            clear_below: false,
+            vi_search: None,
+            last_vi_search: None,
+            arg: None,
+            yanked_arg: None,
+            completer: None,
+            hinter: None,
+            highlighter: None,
+            validator: None,
+            invalid: None,
+            match_brackets: cfg.match_brackets,
+            hint: None,
+            completed: false,
+            listing: None,
+            menu: None,
+            menu_complete: cfg.menu_complete,
+            query_items: cfg.completion_query_items,
+            pending: Vec::new(),
+            width: 80,
+            height: 24,
         }
     }
 
+    /// Sets the number of columns and rows of the terminal.
+    pub fn set_size(&mut self, width: usize, height: usize) {
+        self.width = width;
+        self.height = height;
+    }
+
+    pub fn set_completer(&mut self, completer: &'a dyn Completer) {
+        self.completer = Some(completer);
+    }
+
+    pub fn set_hinter(&mut self, hinter: &'a dyn Hinter) {
+        self.hinter = Some(hinter);
+    }
+
+    pub fn set_highlighter(&mut self, highlighter: &'a dyn Highlighter) {
+        self.highlighter = Some(highlighter);
+    }
+
+    pub fn set_validator(&mut self, validator: &'a dyn Validator) {
+        self.validator = Some(validator);
+    }
+
+    /// Takes output still to be written after the line was accepted.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        let mut output = Vec::new();
+        swap(&mut output, &mut self.pending);
+        output
+    }
+
     pub fn fill<I>(&mut self, it: I) where I: IntoIterator<Item=u8> {
         self.seq.extend(it)
     }
@@ -147,9 +277,35 @@ macro_rules! vi_delete {
     };
 }
 
+/// Takes the finished line, expanding history references if enabled.
+///
+/// A line the validator finds incomplete gets another line instead, and
+/// an invalid one is kept for editing.
+fn accept_line<'a>(ctx: &mut EditCtx<'a>) -> EditResult<bool> {
+    if let Some(validator) = ctx.validator {
+        match validator.validate(ctx.buf.as_str()) {
+            ValidationResult::Valid => {},
+            ValidationResult::Incomplete => {
+                ctx.buf.insert_char_at_cursor('\n');
+                return EditResult::Cont(false);
+            },
+            ValidationResult::Invalid(message) => {
+                ctx.invalid = Some(message);
+                return EditResult::Cont(false);
+            }
+        }
+    }
+    let line = ctx.buf.drain();
+    if ctx.history_expansion {
+        EditResult::Halt(expand(&line, ctx.history))
+    } else {
+        EditResult::Halt(Ok(line))
+    }
+}
+
 fn handle_common<'a>(ctx: &mut EditCtx<'a>, cinstr: instr::CommonInstr) -> EditResult<bool> {
     match cinstr {
-        instr::CommonInstr::Done => EditResult::Halt(Ok(ctx.buf.drain())),
+        instr::CommonInstr::Done => accept_line(ctx),
         instr::CommonInstr::Noop => EditResult::Cont(false),
         instr::CommonInstr::Cancel => EditResult::Halt(Err(Error::Cancel)),
         instr::CommonInstr::Clear => EditResult::Cont(true)
@@ -157,6 +313,31 @@ fn handle_common<'a>(ctx: &mut EditCtx<'a>, cinstr: instr::CommonInstr) -> EditR
 }
 
 fn handle_history<'a>(ctx: &mut EditCtx<'a>, hinstr: instr::HistoryInstr) -> EditResult<bool> {
+    // Up and down move between the lines of the buffer first
+    let within = match hinstr {
+        instr::HistoryInstr::Prev => ctx.buf.text_before_cursor().contains('\n'),
+        instr::HistoryInstr::Next => ctx.buf.text_after_cursor().contains('\n'),
+        _ => false
+    };
+    if within {
+        vi_repeat!(ctx, match hinstr {
+            instr::HistoryInstr::Prev => ctx.buf.move_line_up(),
+            _ => ctx.buf.move_line_down()
+        });
+        return EditResult::Cont(false);
+    }
+    if ctx.history_cursor.position().is_none() {
+        ctx.history_prefix = if ctx.prefix_search && !ctx.buf.is_empty() {
+            Some(ctx.buf.text_before_cursor().to_owned())
+        } else {
+            None
+        };
+    }
+    if let Some(prefix) = ctx.history_prefix.clone() {
+        if let instr::HistoryInstr::Prev | instr::HistoryInstr::Next = hinstr {
+            return handle_history_prefix(ctx, hinstr, &prefix);
+        }
+    }
     match hinstr {
         instr::HistoryInstr::Prev => {
             vi_repeat!(ctx, {
@@ -164,7 +345,7 @@ fn handle_history<'a>(ctx: &mut EditCtx<'a>, hinstr: instr::HistoryInstr) -> Edi
                 if end {
                     ctx.buf.swap()
                 }
-                ctx.history_cursor.get().map(|s| ctx.buf.replace(s));
+                ctx.history_cursor.get().map(|s| ctx.buf.replace(&s));
                 end
             });
             EditResult::Cont(false)
@@ -175,15 +356,286 @@ fn handle_history<'a>(ctx: &mut EditCtx<'a>, hinstr: instr::HistoryInstr) -> Edi
                 if end {
                     ctx.buf.swap()
                 }
-                ctx.history_cursor.get().map(|s| ctx.buf.replace(s));
+                ctx.history_cursor.get().map(|s| ctx.buf.replace(&s));
                 end
             });
             EditResult::Cont(false)
         }
+        instr::HistoryInstr::First => {
+            if let Some(oldest) = ctx.history.len().checked_sub(1) {
+                if ctx.history_cursor.set(oldest) {
+                    ctx.buf.swap()
+                }
+                if let Some(s) = ctx.history_cursor.get() {
+                    ctx.buf.replace(&s);
+                }
+            }
+            EditResult::Cont(false)
+        }
+        instr::HistoryInstr::Last => {
+            if ctx.history_cursor.reset() {
+                ctx.buf.swap()
+            }
+            EditResult::Cont(false)
+        }
+    }
+}
+
+/// Browse the history entries starting with the text that was before the
+/// cursor when browsing started, keeping the cursor in the same column.
+fn handle_history_prefix<'a>(ctx: &mut EditCtx<'a>, hinstr: instr::HistoryInstr, prefix: &str) -> EditResult<bool> {
+    vi_repeat!(ctx, {
+        let end = match hinstr {
+            instr::HistoryInstr::Prev => ctx.history_cursor.incr_prefix(prefix, ctx.buf.as_str()),
+            instr::HistoryInstr::Next => ctx.history_cursor.decr_prefix(prefix, ctx.buf.as_str()),
+            _ => false
+        };
+        if end {
+            ctx.buf.swap()
+        }
+        if let Some(s) = ctx.history_cursor.get() {
+            ctx.buf.replace(&s);
+        }
+        ctx.buf.move_to_byte_pos(prefix.len());
+        end
+    });
+    EditResult::Cont(false)
+}
+
+/// Searches for the current query, starting at the given entry, and records the result.
+fn update_search(search: &mut Search, history: &dyn HistoryStore, start: usize) {
+    match history.search(&search.query, start, search.dir) {
+        Some(found) => {
+            search.found = Some(found);
+            search.failed = false;
+        },
+        None => search.failed = !search.query.is_empty()
+    }
+}
+
+/// Ends the current search, moving the history cursor to the matched entry.
+fn accept_search<'a>(ctx: &mut EditCtx<'a>) {
+    if let Some(Search { found: Some((idx, offset)), .. }) = ctx.search.take() {
+        if ctx.history_cursor.set(idx) {
+            ctx.buf.swap()
+        }
+        if let Some(s) = ctx.history_cursor.get() {
+            ctx.buf.replace(&s);
+        }
+        ctx.buf.move_to_byte_pos(offset);
+    }
+}
+
+fn handle_picker<'a>(ctx: &mut EditCtx<'a>, pinstr: instr::PickerInstr) -> EditResult<bool> {
+    let history = ctx.history;
+    match pinstr {
+        instr::PickerInstr::Start => {
+            ctx.picker = Some(Picker::new(history, ctx.picker_rows));
+        },
+        instr::PickerInstr::Insert(text) => {
+            if let Some(ref mut picker) = ctx.picker {
+                picker.insert(&text, history);
+            }
+        },
+        instr::PickerInstr::DeleteChar => {
+            if let Some(ref mut picker) = ctx.picker {
+                picker.delete_char(history);
+            }
+        },
+        instr::PickerInstr::Up => {
+            if let Some(ref mut picker) = ctx.picker {
+                picker.up();
+            }
+        },
+        instr::PickerInstr::Down => {
+            if let Some(ref mut picker) = ctx.picker {
+                picker.down();
+            }
+        },
+        instr::PickerInstr::Accept => {
+            let selected = ctx.picker.take().and_then(|picker| picker.selected());
+            if let Some(idx) = selected {
+                if ctx.history_cursor.set(idx) {
+                    ctx.buf.swap()
+                }
+                if let Some(s) = ctx.history_cursor.get() {
+                    ctx.buf.replace(&s);
+                }
+            }
+            ctx.clear_below = true;
+        },
+        instr::PickerInstr::Abort => {
+            ctx.picker = None;
+            ctx.clear_below = true;
+        }
+    }
+    EditResult::Cont(false)
+}
+
+/// Moves to the next history entry containing the query in the given
+/// direction, with the cursor at the start of the line.
+fn vi_search_history<'a>(ctx: &mut EditCtx<'a>, query: &str, dir: SearchDirection) -> bool {
+    let start = match (dir, ctx.history_cursor.position()) {
+        (SearchDirection::Reverse, Some(idx)) => Some(idx + 1),
+        (SearchDirection::Reverse, None) => Some(0),
+        (SearchDirection::Forward, Some(idx)) => idx.checked_sub(1),
+        (SearchDirection::Forward, None) => None
+    };
+    match start.and_then(|start| ctx.history.search(query, start, dir)) {
+        Some((idx, _)) => {
+            if ctx.history_cursor.set(idx) {
+                ctx.buf.swap()
+            }
+            if let Some(s) = ctx.history_cursor.get() {
+                ctx.buf.replace(&s);
+            }
+            ctx.buf.move_start();
+            true
+        },
+        None => false
     }
 }
 
+fn handle_vi_search<'a>(ctx: &mut EditCtx<'a>, vinstr: instr::ViSearchInstr) -> EditResult<bool> {
+    match vinstr {
+        instr::ViSearchInstr::Start(dir) => ctx.vi_search = Some((String::new(), dir)),
+        instr::ViSearchInstr::Insert(text) => {
+            if let Some((ref mut query, _)) = ctx.vi_search {
+                query.push_str(&text);
+            }
+        },
+        instr::ViSearchInstr::DeleteChar => {
+            // deleting past the start of the query leaves the search like in vi
+            let empty = match ctx.vi_search {
+                Some((ref mut query, _)) => query.pop().is_none(),
+                None => false
+            };
+            if empty {
+                ctx.vi_search = None;
+                ctx.mode_state = next_vi_mode(ctx.mode_state);
+            }
+        },
+        instr::ViSearchInstr::Execute => {
+            if let Some((query, dir)) = ctx.vi_search.take() {
+                // an empty query searches for the last one again
+                let search = if query.is_empty() {
+                    ctx.last_vi_search.take().map(|(query, _)| (query, dir))
+                } else {
+                    Some((query, dir))
+                };
+                if let Some((query, dir)) = search {
+                    vi_repeat!(ctx, vi_search_history(ctx, &query, dir));
+                    ctx.last_vi_search = Some((query, dir));
+                }
+            }
+            ctx.mode_state = next_vi_mode(ctx.mode_state);
+        },
+        instr::ViSearchInstr::Abort => {
+            ctx.vi_search = None;
+            ctx.mode_state = next_vi_mode(ctx.mode_state);
+        },
+        instr::ViSearchInstr::Repeat | instr::ViSearchInstr::RepeatOpposite => {
+            if let Some((query, dir)) = ctx.last_vi_search.clone() {
+                let dir = match vinstr {
+                    instr::ViSearchInstr::RepeatOpposite => match dir {
+                        SearchDirection::Reverse => SearchDirection::Forward,
+                        SearchDirection::Forward => SearchDirection::Reverse
+                    },
+                    _ => dir
+                };
+                vi_repeat!(ctx, vi_search_history(ctx, &query, dir));
+            }
+            ctx.mode_state = next_vi_mode(ctx.mode_state);
+        }
+    }
+    EditResult::Cont(false)
+}
+
+fn handle_search<'a>(ctx: &mut EditCtx<'a>, sinstr: instr::SearchInstr) -> EditResult<bool> {
+    let history = ctx.history;
+    match sinstr {
+        instr::SearchInstr::Start(dir) => {
+            match ctx.search {
+                Some(ref mut search) => {
+                    // repeat the search from the entry after the current match
+                    let next = match (dir, search.found) {
+                        (SearchDirection::Reverse, Some((idx, _))) => Some(idx + 1),
+                        (SearchDirection::Forward, Some((idx, _))) => idx.checked_sub(1),
+                        (_, None) => Some(search.start)
+                    };
+                    search.dir = dir;
+                    match next {
+                        Some(next) => update_search(search, history, next),
+                        None => search.failed = true
+                    }
+                },
+                None => {
+                    let start = ctx.history_cursor.position().unwrap_or(0);
+                    ctx.search = Some(Search {
+                        query: String::new(),
+                        dir: dir,
+                        start: start,
+                        found: None,
+                        failed: false,
+                    });
+                }
+            }
+        },
+        instr::SearchInstr::Insert(text) => {
+            if let Some(ref mut search) = ctx.search {
+                search.query.push_str(&text);
+                let start = match search.found {
+                    Some((idx, _)) => idx,
+                    None => search.start
+                };
+                update_search(search, history, start);
+            }
+        },
+        instr::SearchInstr::DeleteChar => {
+            if let Some(ref mut search) = ctx.search {
+                search.query.pop();
+                search.found = None;
+                let start = search.start;
+                update_search(search, history, start);
+            }
+        },
+        instr::SearchInstr::Accept => accept_search(ctx),
+        instr::SearchInstr::Abort => ctx.search = None
+    }
+    EditResult::Cont(false)
+}
+
+/// Inserts the hint, or with `word` only its first word, if one is shown.
+fn accept_hint<'a>(ctx: &mut EditCtx<'a>, word: bool) -> bool {
+    let hint = match ctx.hint.take() {
+        Some(hint) => hint,
+        None => return false
+    };
+    let text = if word { first_word(&hint) } else { hint.as_str() };
+    ctx.buf.insert_chars_at_cursor(text);
+    true
+}
+
+/// Asks the hinter for a hint, which is only shown at the end of the line
+/// while editing it.
+fn update_hint<'a>(ctx: &mut EditCtx<'a>) {
+    let editing = matches!(ctx.mode_state, ModeState::Emacs | ModeState::Vi(ViMode::Insert, _));
+    let modal = ctx.search.is_some() || ctx.picker.is_some() || ctx.vi_search.is_some()
+        || ctx.listing.is_some() || ctx.menu.is_some();
+    ctx.hint = match ctx.hinter {
+        Some(hinter) if editing && !modal && ctx.buf.is_cursor_at_end() => {
+            hinter.hint(ctx.buf.as_str(), ctx.history).filter(|hint| !hint.is_empty())
+        },
+        _ => None
+    };
+}
+
 fn handle_move_cursor<'a>(ctx: &mut EditCtx<'a>, mcinstr: instr::MoveCursorInstr) -> EditResult<bool> {
+    if let instr::MoveCursorInstr::Right | instr::MoveCursorInstr::End = mcinstr {
+        if accept_hint(ctx, false) {
+            return EditResult::Cont(false);
+        }
+    }
     match mcinstr {
         instr::MoveCursorInstr::Left => {
             let mut dc = ctx.buf.start_delete();
@@ -222,9 +674,231 @@ fn handle_move_cursor<'a>(ctx: &mut EditCtx<'a>, mcinstr: instr::MoveCursorInstr
     }
 }
 
+/// Returns the word with the given index, or the last word, of a history entry.
+fn history_word(history: &dyn HistoryStore, idx: usize, n: Option<u32>) -> Option<String> {
+    let mut words = match history.get(idx) {
+        Some(entry) => split_words(&entry.line),
+        None => return None
+    };
+    match n {
+        Some(n) if (n as usize) < words.len() => Some(words.swap_remove(n as usize)),
+        Some(_) => None,
+        None => words.pop()
+    }
+}
+
+/// Inserts the last word of the previous line, or the word given by the
+/// argument. Repeating it replaces the word with the one of the next
+/// older line.
+fn yank_last_arg<'a>(ctx: &mut EditCtx<'a>, arg: Option<u32>) {
+    let previous = ctx.yanked_arg.take();
+    let idx = previous.as_ref().map_or(0, |&(idx, _)| idx + 1);
+    match history_word(ctx.history, idx, arg) {
+        Some(word) => {
+            let pos = ctx.buf.text_before_cursor().len();
+            let range = previous.map_or(pos..pos, |(_, range)| range);
+            ctx.buf.replace_range(range.start, range.end, &word);
+            ctx.yanked_arg = Some((idx, range.start..range.start + word.len()));
+        },
+        None => ctx.yanked_arg = previous
+    }
+}
+
+/// Rows of completions listed page by page below the line.
+struct Listing {
+    rows: Vec<String>,
+    next: usize,
+    // whether the user is still asked if the completions should be listed
+    asking: bool,
+}
+
+/// Lists completions below the line, asking first if there are many.
+fn start_listing<'a>(ctx: &mut EditCtx<'a>, mut candidates: Vec<Candidate>) {
+    candidates.sort_by(|a, b| a.display.cmp(&b.display));
+    candidates.dedup();
+    group_candidates(&mut candidates);
+    let listing = Listing {
+        rows: format_candidates(&candidates, ctx.width, None).0,
+        next: 0,
+        asking: candidates.len() >= ctx.query_items,
+    };
+    if listing.asking {
+        let question = format!("\r\nDisplay all {} possibilities? (y or n)", candidates.len());
+        ctx.pending.extend(question.as_bytes());
+        ctx.listing = Some(listing);
+    } else {
+        ctx.pending.extend(b"\r\n");
+        show_rows(ctx, listing, usize::MAX);
+    }
+}
+
+/// Prints up to `count` rows, fewer if they would scroll the first one out
+/// of view, followed by `--More--` while rows remain.
+fn show_rows<'a>(ctx: &mut EditCtx<'a>, mut listing: Listing, count: usize) {
+    let page = ctx.height.saturating_sub(1).max(1);
+    let end = listing.rows.len().min(listing.next + count.min(page));
+    ctx.pending.extend(listing.rows[listing.next..end].join("\r\n").as_bytes());
+    ctx.pending.extend(b"\r\n");
+    listing.next = end;
+    if end < listing.rows.len() {
+        ctx.pending.extend(b"--More--");
+        ctx.listing = Some(listing);
+    }
+}
+
+fn handle_listing<'a>(ctx: &mut EditCtx<'a>, linstr: instr::ListingInstr) -> EditResult<bool> {
+    let mut listing = match ctx.listing.take() {
+        Some(listing) => listing,
+        None => return EditResult::Cont(false)
+    };
+    // leave the line with the question or erase `--More--`
+    if listing.asking {
+        ctx.pending.extend(b"\r\n");
+    } else {
+        ctx.pending.extend(b"\r\x1b[K");
+    }
+    match linstr {
+        instr::ListingInstr::Yes | instr::ListingInstr::NextPage => {
+            listing.asking = false;
+            show_rows(ctx, listing, usize::MAX)
+        },
+        instr::ListingInstr::NextLine => show_rows(ctx, listing, 1),
+        instr::ListingInstr::No => {}
+    }
+    EditResult::Cont(false)
+}
+
+/// Completions that replace the completed text in turn, shown in a grid
+/// below the line.
+struct Menu {
+    start: usize,
+    // end of the text currently in place of the completed one
+    end: usize,
+    original: String,
+    candidates: Vec<Candidate>,
+    selected: Option<usize>,
+}
+
+/// Ends menu completion, keeping the selected candidate.
+fn close_menu<'a>(ctx: &mut EditCtx<'a>) {
+    if ctx.menu.take().is_some() {
+        ctx.clear_below = true;
+    }
+}
+
+fn handle_menu<'a>(ctx: &mut EditCtx<'a>, minstr: instr::MenuInstr) -> EditResult<bool> {
+    if ctx.menu.is_none() {
+        match minstr {
+            instr::MenuInstr::Next | instr::MenuInstr::Prev => start_menu(ctx),
+            _ => {}
+        }
+    }
+    let width = ctx.width;
+    let mut menu = match ctx.menu.take() {
+        Some(menu) => menu,
+        None => return EditResult::Cont(false)
+    };
+    let count = menu.candidates.len();
+    let cols = candidate_columns(&menu.candidates, width);
+    let selected = match (minstr, menu.selected) {
+        (instr::MenuInstr::Accept, _) => {
+            ctx.clear_below = true;
+            return EditResult::Cont(false);
+        },
+        (instr::MenuInstr::Abort, _) => {
+            ctx.buf.replace_range(menu.start, menu.end, &menu.original);
+            ctx.clear_below = true;
+            return EditResult::Cont(false);
+        },
+        (instr::MenuInstr::Prev, None) | (instr::MenuInstr::Left, None) | (instr::MenuInstr::Up, None) => count - 1,
+        (_, None) => 0,
+        (instr::MenuInstr::Next, Some(i)) | (instr::MenuInstr::Right, Some(i)) => (i + 1) % count,
+        (instr::MenuInstr::Prev, Some(i)) | (instr::MenuInstr::Left, Some(i)) => (i + count - 1) % count,
+        // wrap around to the other end of the column
+        (instr::MenuInstr::Down, Some(i)) if i + cols < count => i + cols,
+        (instr::MenuInstr::Down, Some(i)) => i % cols,
+        (instr::MenuInstr::Up, Some(i)) if i >= cols => i - cols,
+        (instr::MenuInstr::Up, Some(i)) => {
+            let rows = (count - 1 - i % cols) / cols;
+            i % cols + rows * cols
+        }
+    };
+    let candidate = &menu.candidates[selected].replacement;
+    ctx.buf.replace_range(menu.start, menu.end, candidate);
+    menu.end = menu.start + candidate.len();
+    menu.selected = Some(selected);
+    ctx.menu = Some(menu);
+    EditResult::Cont(false)
+}
+
+/// Whether a completer returned a span of the line it may replace.
+fn is_valid_span(line: &str, span: &Range<usize>) -> bool {
+    span.start <= span.end && span.end <= line.len() &&
+        line.is_char_boundary(span.start) && line.is_char_boundary(span.end)
+}
+
+/// Starts menu completion, unless there are no candidates to choose from.
+fn start_menu<'a>(ctx: &mut EditCtx<'a>) {
+    let completer = match ctx.completer {
+        Some(completer) => completer,
+        None => return
+    };
+    let pos = ctx.buf.text_before_cursor().len();
+    let mut completion = completer.complete(ctx.buf.as_str(), pos);
+    let span = completion.span;
+    if !is_valid_span(ctx.buf.as_str(), &span) {
+        return;
+    }
+    group_candidates(&mut completion.candidates);
+    match completion.candidates.len() {
+        0 => {},
+        1 => ctx.buf.replace_range(span.start, span.end, &completion.candidates[0].replacement),
+        _ => ctx.menu = Some(Menu {
+            start: span.start,
+            end: span.end,
+            original: ctx.buf.as_str()[span.start..span.end].to_owned(),
+            candidates: completion.candidates,
+            selected: None,
+        })
+    }
+}
+
+/// Completes the text before the cursor with the longest prefix the
+/// candidates share. If that adds nothing, completing again right away
+/// lists the candidates.
+fn complete<'a>(ctx: &mut EditCtx<'a>, repeated: bool) {
+    let completer = match ctx.completer {
+        Some(completer) => completer,
+        None => return
+    };
+    let pos = ctx.buf.text_before_cursor().len();
+    let completion = completer.complete(ctx.buf.as_str(), pos);
+    let span = completion.span;
+    if completion.candidates.is_empty() || !is_valid_span(ctx.buf.as_str(), &span) {
+        return;
+    }
+    let prefix = {
+        let replacements: Vec<&str> = completion.candidates.iter().map(|c| c.replacement.as_str()).collect();
+        common_prefix(&replacements).to_owned()
+    };
+    let current = &ctx.buf.as_str()[span.start..span.end];
+    if prefix.len() > current.len() || (completion.candidates.len() == 1 && prefix != current) {
+        ctx.buf.replace_range(span.start, span.end, &prefix);
+    } else if repeated && completion.candidates.len() > 1 {
+        start_listing(ctx, completion.candidates);
+    }
+    ctx.completed = true;
+}
+
 fn handle<'a>(ctx: &mut EditCtx<'a>, ins: instr::Instr) -> EditResult<bool> {
     use self::EditResult::*;
 
+    // the argument and yanked word only apply to the next instruction
+    let arg = ctx.arg.take();
+    let yanked_arg = ctx.yanked_arg.take();
+    let completed = ctx.completed;
+    ctx.completed = false;
+    ctx.invalid = None;
     match ins {
         instr::Instr::Common(cinstr) => handle_common(ctx, cinstr),
         instr::Instr::DoneOrEof => {
@@ -232,8 +906,40 @@ fn handle<'a>(ctx: &mut EditCtx<'a>, ins: instr::Instr) -> EditResult<bool> {
                 Halt(Err(Error::EndOfFile))
             }
             else {
-                Halt(Ok(ctx.buf.drain()))
+                accept_line(ctx)
+            }
+        }
+        instr::Instr::Argument(digit) => {
+            ctx.arg = Some(arg.unwrap_or(0).saturating_mul(10).saturating_add(digit));
+            // an argument for a repeated M-. still replaces the yanked word
+            ctx.yanked_arg = yanked_arg;
+            Cont(false)
+        }
+        instr::Instr::YankLastArg => {
+            ctx.yanked_arg = yanked_arg;
+            yank_last_arg(ctx, arg);
+            Cont(false)
+        }
+        instr::Instr::YankNthArg => {
+            if let Some(word) = history_word(ctx.history, 0, Some(arg.unwrap_or(1))) {
+                ctx.buf.insert_chars_at_cursor(&word);
+            }
+            Cont(false)
+        }
+        instr::Instr::Complete if ctx.menu_complete => handle_menu(ctx, instr::MenuInstr::Next),
+        instr::Instr::Complete => {
+            complete(ctx, completed);
+            Cont(false)
+        }
+        instr::Instr::Menu(minstr) => handle_menu(ctx, minstr),
+        instr::Instr::ExpandHistory => {
+            // leave the line untouched if it cannot be expanded, showing why below it
+            match expand(ctx.buf.as_str(), ctx.history) {
+                Ok(line) => ctx.buf.replace(&line),
+                Err(Error::HistoryExpansion(msg)) => ctx.invalid = Some(msg),
+                Err(_) => {}
             }
+            Cont(false)
         }
         instr::Instr::DeleteCharLeftOfCursor => {
             vi_repeat!(ctx, ctx.buf.delete_char_left_of_cursor());
@@ -281,6 +987,10 @@ fn handle<'a>(ctx: &mut EditCtx<'a>, ins: instr::Instr) -> EditResult<bool> {
         }
         instr::Instr::MoveCursor(mcinstr) => handle_move_cursor(ctx, mcinstr),
         instr::Instr::History(hinstr) => handle_history(ctx, hinstr),
+        instr::Instr::Search(sinstr) => handle_search(ctx, sinstr),
+        instr::Instr::Picker(pinstr) => handle_picker(ctx, pinstr),
+        instr::Instr::ViSearch(vinstr) => handle_vi_search(ctx, vinstr),
+        instr::Instr::Listing(linstr) => handle_listing(ctx, linstr),
         instr::Instr::NormalMode => {
             if let ModeState::Vi(ViMode::Insert, _) = ctx.mode_state {
                 // cursor moves left when leaving insert mode
@@ -382,6 +1092,36 @@ fn handle<'a>(ctx: &mut EditCtx<'a>, ins: instr::Instr) -> EditResult<bool> {
             ctx.exclude_eol();
             Cont(false)
         }
+        instr::Instr::MoveToMatchingBracket => {
+            {
+                let mut dc = ctx.buf.start_delete();
+                let moved = dc.move_to_matching_bracket();
+                match ctx.mode_state {
+                    ModeState::Vi(ViMode::Delete, _)
+                    | ModeState::Vi(ViMode::Change, _) if moved => {
+                        dc.delete_inclusive();
+                    }
+                    _ => {}
+                }
+            }
+            ctx.mode_state = next_vi_mode(ctx.mode_state);
+            ctx.exclude_eol();
+            Cont(false)
+        },
+        instr::Instr::MoveOverBracketsForward => {
+            ctx.buf.move_over_brackets_forward();
+            Cont(false)
+        },
+        instr::Instr::MoveOverBracketsBackward => {
+            ctx.buf.move_over_brackets_backward();
+            Cont(false)
+        },
+        instr::Instr::MoveWordForward => {
+            if !accept_hint(ctx, true) {
+                ctx.buf.move_word_forward();
+            }
+            Cont(false)
+        },
         instr::Instr::MoveWordRight => {
             {
                 let mut dc = ctx.buf.start_delete();
@@ -532,6 +1272,151 @@ fn handle<'a>(ctx: &mut EditCtx<'a>, ins: instr::Instr) -> EditResult<bool> {
     }
 }
 
+fn render<'a>(ctx: &mut EditCtx<'a>, clear: bool) -> Vec<u8> {
+    // output like listed completions comes first, and the line is drawn
+    // again after it unless the listing waits for input
+    let mut pending = Vec::new();
+    if !ctx.pending.is_empty() {
+        // the output starts after the last row of the line
+        let mut line = Builder::new();
+        line.cursor_down(ctx.rows.count.saturating_sub(ctx.rows.cursor + 1));
+        pending = line.build();
+        pending.append(&mut ctx.pending);
+        ctx.rows = Rows::default();
+    }
+    if ctx.listing.is_none() {
+        // the lines above the last line of the prompt are only drawn when
+        // nothing is left of them
+        let mut line = Builder::new();
+        if clear {
+            line.clear_screen();
+            ctx.rows = Rows::default();
+        }
+        if clear || !pending.is_empty() || !ctx.prompt_drawn {
+            line.append(&ctx.prompt.above);
+            ctx.prompt_drawn = true;
+        }
+        pending.extend(line.build());
+        pending.extend(render_edit_line(ctx));
+    }
+    pending
+}
+
+/// Whether the character before the cursor closes a pair of brackets that
+/// the cursor is not on.
+fn closes_bracket_before_cursor(buf: &Buffer) -> bool {
+    let pos = buf.text_before_cursor().len();
+    buf.matching_bracket(pos).is_none() && buf.text_before_cursor().char_indices().next_back()
+        .is_some_and(|(i, c)| (c == ')' || c == ']' || c == '}') && buf.matching_bracket(i).is_some())
+}
+
+/// Asks the highlighter for the styled parts of the line, and highlights
+/// the bracket matching the one at the cursor.
+fn highlight<'a>(ctx: &EditCtx<'a>) -> Vec<Span> {
+    let mut spans = match ctx.highlighter {
+        Some(highlighter) => highlighter.highlight(ctx.buf.as_str()),
+        None => Vec::new()
+    };
+    if ctx.match_brackets {
+        let mut pos = ctx.buf.text_before_cursor().len();
+        // while typing, the bracket just closed is matched too
+        let typing = !matches!(ctx.mode_state, ModeState::Vi(ViMode::Normal, _));
+        if typing && closes_bracket_before_cursor(&ctx.buf) {
+            pos = ctx.buf.text_before_cursor().char_indices().next_back().map_or(0, |(i, _)| i);
+        }
+        let matched = ctx.buf.matching_bracket(pos);
+        if let Some(i) = matched {
+            spans.push(Span::new(i..i + 1, Style { inverted: true, ..Style::new() }));
+        }
+    }
+    spans
+}
+
+/// Moves back to the first row of the line, erasing the rows below it,
+/// for views taking up a single row.
+fn leave_rows<'a>(ctx: &mut EditCtx<'a>) -> Vec<u8> {
+    let mut line = Builder::new();
+    line.cursor_up(ctx.rows.cursor);
+    if ctx.rows.count + ctx.rows.below > 1 {
+        line.carriage_return();
+        line.erase_below();
+    }
+    ctx.rows = Rows::default();
+    line.build()
+}
+
+fn render_edit_line<'a>(ctx: &mut EditCtx<'a>) -> Vec<u8> {
+    // other views erase the right prompt along with the line
+    ctx.right_prompt_shown = false;
+    if ctx.picker.is_some() || ctx.vi_search.is_some() || ctx.search.is_some() {
+        let mut line = leave_rows(ctx);
+        if let Some(ref picker) = ctx.picker {
+            line.extend(picker.render(ctx.history, ctx.width, false));
+        } else if let Some((ref query, dir)) = ctx.vi_search {
+            let prompt = match dir {
+                SearchDirection::Reverse => "/",
+                SearchDirection::Forward => "?"
+            };
+            line.extend(render_line(prompt, prompt.len(), query, UnicodeWidthStr::width(query.as_str()), false));
+        } else if let Some(ref search) = ctx.search {
+            let matched = search.found.and_then(|(idx, offset)| {
+                ctx.history.get(idx).map(|e| (line_of(e), offset))
+            });
+            let matched = matched.as_ref().map(|&(ref line, offset)| (&**line, offset));
+            line.extend(ctx.buf.get_search_line(&search.query, search.dir, search.failed, matched, false));
+        }
+        return line;
+    }
+    if ctx.clear_below {
+        // clear the rows the picker used below the line
+        ctx.clear_below = false;
+        let mut line = Builder::new();
+        line.cursor_up(ctx.rows.cursor);
+        line.carriage_return();
+        line.erase_below();
+        ctx.rows.cursor = 0;
+        let mut line = line.build();
+        line.extend(get_line(ctx, Vec::new()));
+        return line;
+    }
+    let below = match ctx.menu {
+        Some(ref menu) => {
+            let (rows, selected_row) = format_candidates(&menu.candidates, ctx.width, menu.selected);
+            // keep the selected row in view, leaving rows for the line
+            let lines = (ctx.buf.as_str().matches('\n').count() + 1).max(ctx.rows.count);
+            let max_rows = ctx.height.saturating_sub(lines).max(1);
+            let selected_row = selected_row.unwrap_or(0);
+            let first = (selected_row + 1).saturating_sub(max_rows);
+            let end = rows.len().min(first + max_rows);
+            rows[first..end].to_vec()
+        },
+        None => ctx.invalid.iter().cloned().collect()
+    };
+    get_line(ctx, below)
+}
+
+/// Renders the line with the hint, the rows below it and, if it fits,
+/// the right prompt.
+fn get_line<'a>(ctx: &mut EditCtx<'a>, below: Vec<String>) -> Vec<u8> {
+    let spans = highlight(ctx);
+    let first = ctx.buf.as_str().split('\n').next().unwrap_or("");
+    let mut used = ctx.prompt.width + UnicodeWidthStr::width(first);
+    if first.len() == ctx.buf.as_str().len() {
+        used += ctx.hint.as_ref().map_or(0, |hint| UnicodeWidthStr::width(hint.as_str()));
+    }
+    let right = ctx.prompt.right_column(used, ctx.width);
+    ctx.right_prompt_shown = right.is_some();
+    let deco = Decorations {
+        prompt: &ctx.prompt,
+        spans: &spans,
+        hint: ctx.hint.as_deref(),
+        right: right,
+        below: &below,
+        width: ctx.width
+    };
+    ctx.buf.get_line(&deco, &mut ctx.rows, false)
+}
+
 pub fn edit<'a>(ctx: &mut EditCtx<'a>) -> EditResult<Vec<u8>> {
     let res = match parse(&ctx.seq, ctx.enc) {
         Err(ParseError::Error(len)) => {
@@ -542,7 +1427,25 @@ pub fn edit<'a>(ctx: &mut EditCtx<'a>) -> EditResult<Vec<u8>> {
         },
         Err(ParseError::Incomplete) => EditResult::Cont(false),
         Ok(ParseSuccess(token, len)) => {
-            let ins = instr::interpret_token(token, ctx.mode_state);
+            let search_ins = match ctx.search {
+                Some(_) => instr::search_mode(&token),
+                None if ctx.menu.is_some() => instr::menu_mode(&token),
+                None => None
+            };
+            let ins = match search_ins {
+                Some(ins) => ins,
+                None if ctx.picker.is_some() => instr::picker_mode(token),
+                None if ctx.listing.is_some() => {
+                    instr::listing_mode(token, ctx.listing.as_ref().is_some_and(|l| l.asking))
+                },
+                None if ctx.vi_search.is_some() => instr::vi_search_mode(token),
+                None => {
+                    // any other key ends the search or menu and is handled as usual
+                    accept_search(ctx);
+                    close_menu(ctx);
+                    instr::interpret_token(token, ctx.mode_state)
+                }
+            };
             let res = handle(ctx, ins);
             for _ in 0..len {
                 ctx.seq.remove(0);
@@ -551,7 +1454,28 @@ pub fn edit<'a>(ctx: &mut EditCtx<'a>) -> EditResult<Vec<u8>> {
         }
     };
     match res {
-        EditResult::Cont(clear) => EditResult::Cont(ctx.buf.get_line(ctx.prompt, clear)),
-        EditResult::Halt(res) => EditResult::Halt(res)
+        EditResult::Cont(clear) => {
+            update_hint(ctx);
+            EditResult::Cont(render(ctx, clear))
+        },
+        EditResult::Halt(res) => {
+            // leave the cursor after the text, erasing the hint, the rows
+            // below and the right prompt
+            let erase_right = ctx.right_prompt_shown && ctx.transient_right_prompt;
+            let rows = ctx.rows;
+            let down = rows.count.saturating_sub(rows.cursor + 1);
+            if ctx.hint.take().is_some() || erase_right || rows.below > 0 || down > 0 {
+                let mut line = Builder::new();
+                line.cursor_down(rows.end_row.saturating_sub(rows.cursor));
+                line.set_cursor_pos(rows.end);
+                if rows.below > 0 || rows.count > rows.end_row + 1 {
+                    line.erase_below();
+                } else {
+                    line.erase_to_right();
+                }
+                ctx.pending.extend(line.build());
+            }
+            EditResult::Halt(res)
+        }
     }
 }
@@ -1,6 +1,8 @@
 use std::error;
 use std::fmt;
+use std::io;
 use nix;
+use nix::errno::{Errno, EIO};
 
 #[derive(Debug, PartialEq)]
 pub enum Error {
@@ -8,7 +10,9 @@ pub enum Error {
     Cancel,
     EndOfFile,
     UnsupportedTerm,
-    ParseError
+    ParseError,
+    InvalidHistory(usize),
+    HistoryExpansion(String)
 }
 
 impl fmt::Display for Error {
@@ -18,7 +22,9 @@ impl fmt::Display for Error {
             Error::Cancel          => write!(f, "Cancelled"),
             Error::EndOfFile       => write!(f, "End of file"),
             Error::UnsupportedTerm => write!(f, "Unsupported terminal type"),
-            Error::ParseError      => write!(f, "Encountered unknown sequence")
+            Error::ParseError      => write!(f, "Encountered unknown sequence"),
+            Error::InvalidHistory(line) => write!(f, "Invalid history entry on line {}", line),
+            Error::HistoryExpansion(ref msg) => write!(f, "History expansion failed: {}", msg)
         }
     }
 }
@@ -30,7 +36,9 @@ impl error::Error for Error {
             Error::Cancel          => "cancelled",
             Error::EndOfFile       => "end of file",
             Error::UnsupportedTerm => "unsupported terminal type",
-            Error::ParseError      => "unknown sequence"
+            Error::ParseError      => "unknown sequence",
+            Error::InvalidHistory(_) => "invalid history entry",
+            Error::HistoryExpansion(_) => "history expansion failed"
         }
     }
 }
@@ -40,3 +48,10 @@ impl From<nix::Error> for Error {
         Error::ErrNo(err)
     }
 }
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        let errno = err.raw_os_error().map(Errno::from_i32).unwrap_or(EIO);
+        Error::ErrNo(nix::Error::from_errno(errno))
+    }
+}
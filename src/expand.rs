@@ -0,0 +1,268 @@
+use error::Error;
+use history::{HistoryStore, line_of};
+
+/// Expands bash-style history references in a line.
+///
+/// Supported are the event designators `!!`, `!n`, `!-n`, `!string` and
+/// `!?string?`, optionally followed by a word designator such as `:0`, `:^`,
+/// `:$`, `:*`, `:n-m` or `:n*`, the shortcuts `!$`, `!^` and `!*`, and the
+/// quick substitution `^old^new^` at the start of the line. Nothing is
+/// expanded within single quotes or after a backslash.
+pub fn expand(line: &str, history: &dyn HistoryStore) -> Result<String, Error> {
+    if let Some(subst) = line.strip_prefix('^') {
+        return quick_substitution(subst, history);
+    }
+    let chars: Vec<char> = line.chars().collect();
+    let mut expanded = String::with_capacity(line.len());
+    let mut in_quotes = false;
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' if !in_quotes && i + 1 < chars.len() => {
+                expanded.push(chars[i]);
+                expanded.push(chars[i + 1]);
+                i += 2;
+            },
+            '\'' => {
+                in_quotes = !in_quotes;
+                expanded.push('\'');
+                i += 1;
+            },
+            '!' if !in_quotes && starts_event(chars.get(i + 1)) => {
+                let (text, next) = try!(expand_reference(&chars, i + 1, history));
+                expanded.push_str(&text);
+                i = next;
+            },
+            c => {
+                expanded.push(c);
+                i += 1;
+            }
+        }
+    }
+    Ok(expanded)
+}
+
+/// A `!` followed by whitespace, `=`, `(` or nothing is taken literally.
+fn starts_event(c: Option<&char>) -> bool {
+    match c {
+        None => false,
+        Some(&c) => !(c.is_whitespace() || c == '=' || c == '(')
+    }
+}
+
+fn event_not_found(event: &str) -> Error {
+    Error::HistoryExpansion(format!("!{}: event not found", event))
+}
+
+/// Expands the reference whose event designator starts at `start`, right
+/// after the `!`, returning the expansion and the index following it.
+fn expand_reference(chars: &[char], start: usize, history: &dyn HistoryStore)
+                    -> Result<(String, usize), Error> {
+    let mut i = start;
+    let idx = match chars[i] {
+        '!' => {
+            i += 1;
+            Some(0)
+        },
+        // shortcuts for word designators of the previous line
+        '$' | '^' | '*' => Some(0),
+        '?' => {
+            let end = chars[i + 1..].iter().position(|&c| c == '?').map(|p| i + 1 + p);
+            let query: String = chars[i + 1..end.unwrap_or(chars.len())].iter().cloned().collect();
+            i = end.map_or(chars.len(), |e| e + 1);
+            (0..history.len()).find(|&idx| {
+                history.get(idx).is_some_and(|e| e.line.contains(&query))
+            })
+        },
+        '-' | '0'..='9' => {
+            let negative = chars[i] == '-';
+            if negative {
+                i += 1;
+            }
+            let digits: String = chars[i..].iter().take_while(|c| c.is_ascii_digit()).cloned().collect();
+            i += digits.len();
+            match digits.parse::<usize>() {
+                Ok(0) | Err(_) => None,
+                Ok(n) if negative => Some(n - 1),
+                // events are numbered starting with the oldest line
+                Ok(n) => history.len().checked_sub(n)
+            }
+        },
+        _ => {
+            let prefix: String = chars[i..].iter()
+                .take_while(|&&c| !c.is_whitespace() && c != ':')
+                .cloned().collect();
+            i += prefix.chars().count();
+            (0..history.len()).find(|&idx| {
+                history.get(idx).is_some_and(|e| e.line.starts_with(&prefix))
+            })
+        }
+    };
+    let event: String = chars[start..i].iter().cloned().collect();
+    let line = match idx.and_then(|idx| history.get(idx)) {
+        Some(entry) => entry.line.clone(),
+        None => return Err(event_not_found(&event))
+    };
+
+    // `!$`, `!^` and `!*` designate words without a colon
+    let designator_start = match chars.get(i) {
+        Some(&':') if chars.get(i + 1).is_some_and(|&c| is_designator_start(c)) => i + 1,
+        Some(&c) if i == start && is_designator_start(c) => i,
+        _ => return Ok((line, i))
+    };
+    let mut j = designator_start;
+    while j < chars.len() && is_designator_start(chars[j]) {
+        j += 1;
+    }
+    let designator: String = chars[designator_start..j].iter().cloned().collect();
+    let words = split_words(&line);
+    match select_words(&words, &designator) {
+        Some(selected) => Ok((selected, j)),
+        None => Err(Error::HistoryExpansion(format!("{}: bad word specifier", designator)))
+    }
+}
+
+fn is_designator_start(c: char) -> bool {
+    c == '^' || c == '$' || c == '*' || c == '-' || c.is_ascii_digit()
+}
+
+/// Selects the words named by a word designator and joins them with spaces.
+fn select_words(words: &[String], designator: &str) -> Option<String> {
+    let last = match words.len() {
+        0 => return None,
+        n => n - 1
+    };
+    let parse_bound = |s: &str, default: usize| -> Option<usize> {
+        match s {
+            "" => Some(default),
+            "^" => Some(1),
+            "$" => Some(last),
+            _ => s.parse().ok()
+        }
+    };
+    let (from, to) = match designator {
+        // all arguments, which may be none at all
+        "*" => {
+            return Some(words[1..].join(" "));
+        },
+        d if d.ends_with('*') && d.len() > 1 => {
+            (try_bound(parse_bound(&d[..d.len() - 1], 0)), last)
+        },
+        d if d.contains('-') && d != "-" => {
+            let dash = d.find('-').unwrap();
+            let from = try_bound(parse_bound(&d[..dash], 0));
+            // `n-` leaves out the last word
+            let to = try_bound(parse_bound(&d[dash + 1..], last.saturating_sub(1)));
+            (from, to)
+        },
+        d => {
+            let n = try_bound(parse_bound(d, last));
+            (n, n)
+        }
+    };
+    if from > to || to > last || from == usize::MAX {
+        return None;
+    }
+    Some(words[from..to + 1].join(" "))
+}
+
+/// Maps a missing bound to an index that fails the range check.
+fn try_bound(bound: Option<usize>) -> usize {
+    bound.unwrap_or(usize::MAX)
+}
+
+/// Splits a line into words at whitespace outside of quotes.
+pub fn split_words(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut quote = None;
+    for c in line.chars() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {},
+            None if c == '\'' || c == '"' => quote = Some(c),
+            None if c.is_whitespace() => {
+                if !word.is_empty() {
+                    words.push(word);
+                    word = String::new();
+                }
+                continue;
+            },
+            None => {}
+        }
+        word.push(c);
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+/// Replaces the first occurrence of `old` in the previous line with `new`,
+/// given `old^new^rest` with the leading `^` already removed.
+fn quick_substitution(subst: &str, history: &dyn HistoryStore) -> Result<String, Error> {
+    let mut parts = subst.splitn(3, '^');
+    let old = parts.next().unwrap_or("");
+    let new = parts.next().unwrap_or("");
+    let rest = parts.next().unwrap_or("");
+    let line = match history.get(0).map(line_of) {
+        Some(line) => line,
+        None => return Err(event_not_found("!"))
+    };
+    match line.find(old) {
+        Some(pos) if !old.is_empty() => {
+            Ok(format!("{}{}{}{}", &line[..pos], new, &line[pos + old.len()..], rest))
+        },
+        _ => Err(Error::HistoryExpansion(format!(":s^{}^{}: substitution failed", old, new)))
+    }
+}
+
+#[cfg(test)]
+fn test_history() -> ::history::MemoryHistory {
+    ::history::test_history(&["ls -la /tmp", "git commit -m 'a b'", "echo one two three"])
+}
+
+#[test]
+fn expand_events() {
+    let h = test_history();
+    assert_eq!(expand("sudo !!", &h), Ok("sudo echo one two three".to_string()));
+    assert_eq!(expand("!1", &h), Ok("ls -la /tmp".to_string()));
+    assert_eq!(expand("!-2", &h), Ok("git commit -m 'a b'".to_string()));
+    assert_eq!(expand("!gi --amend", &h), Ok("git commit -m 'a b' --amend".to_string()));
+    assert_eq!(expand("!?la?", &h), Ok("ls -la /tmp".to_string()));
+    assert_eq!(expand("no events", &h), Ok("no events".to_string()));
+    assert_eq!(expand("a ! b != c !(d)", &h), Ok("a ! b != c !(d)".to_string()));
+    assert_eq!(expand("echo '!!' \\!!", &h), Ok("echo '!!' \\!!".to_string()));
+}
+
+#[test]
+fn expand_words() {
+    let h = test_history();
+    assert_eq!(expand("cat !$", &h), Ok("cat three".to_string()));
+    assert_eq!(expand("cat !^", &h), Ok("cat one".to_string()));
+    assert_eq!(expand("x !*", &h), Ok("x one two three".to_string()));
+    assert_eq!(expand("!!:0", &h), Ok("echo".to_string()));
+    assert_eq!(expand("!!:1-2", &h), Ok("one two".to_string()));
+    assert_eq!(expand("!!:2*", &h), Ok("two three".to_string()));
+    assert_eq!(expand("!!:1-", &h), Ok("one two".to_string()));
+    assert_eq!(expand("!git:$", &h), Ok("'a b'".to_string()));
+    assert_eq!(expand("!ls:2 !-3:1", &h), Ok("/tmp -la".to_string()));
+    assert_eq!(expand("!!:9", &h), Err(Error::HistoryExpansion("9: bad word specifier".to_string())));
+}
+
+#[test]
+fn expand_errors() {
+    let h = test_history();
+    assert_eq!(expand("!svn", &h), Err(Error::HistoryExpansion("!svn: event not found".to_string())));
+    assert_eq!(expand("!9", &h), Err(Error::HistoryExpansion("!9: event not found".to_string())));
+    assert_eq!(expand("!-0", &h), Err(Error::HistoryExpansion("!-0: event not found".to_string())));
+}
+
+#[test]
+fn expand_quick_substitution() {
+    let h = test_history();
+    assert_eq!(expand("^two^2", &h), Ok("echo one 2 three".to_string()));
+    assert_eq!(expand("^two^2^ four", &h), Ok("echo one 2 three four".to_string()));
+    assert_eq!(expand("^six^6", &h),
+               Err(Error::HistoryExpansion(":s^six^6: substitution failed".to_string())));
+}
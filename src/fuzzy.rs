@@ -0,0 +1,333 @@
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use unicode_width::UnicodeWidthStr;
+
+use builder::Builder;
+use complete::truncate;
+use history::HistoryStore;
+
+/// The number of best matches the picker keeps around for scrolling.
+const MAX_MATCHES: usize = 256;
+
+const SCORE_MATCH: i64 = 16;
+const BONUS_CONSECUTIVE: i64 = 8;
+const BONUS_WORD_START: i64 = 8;
+const PENALTY_GAP: i64 = 1;
+
+fn chars_match(q: char, c: char, ignore_case: bool) -> bool {
+    if q == c || !ignore_case {
+        q == c
+    } else if c.is_ascii() {
+        q == c.to_ascii_lowercase()
+    } else {
+        c.to_lowercase().eq(Some(q))
+    }
+}
+
+/// Splits a query into characters to match, ignoring case unless the
+/// query contains an uppercase letter.
+fn prepare_query(query: &str) -> (Vec<char>, bool) {
+    if query.chars().any(char::is_uppercase) {
+        (query.chars().collect(), false)
+    } else {
+        (query.chars().flat_map(char::to_lowercase).collect(), true)
+    }
+}
+
+/// Scores how well `query` matches `line` as a subsequence, or returns
+/// `None` if it does not match at all.
+///
+/// Matches are rewarded for being consecutive or at the start of a word,
+/// and penalised for gaps between them. The score is computed for the
+/// shortest window ending at the first complete match.
+fn score_chars(query: &[char], ignore_case: bool, line: &str) -> Option<i64> {
+    if query.is_empty() {
+        return Some(0);
+    }
+
+    // find the end of the first match
+    let mut n = 0;
+    let mut end = 0;
+    for (i, c) in line.char_indices() {
+        if chars_match(query[n], c, ignore_case) {
+            n += 1;
+            if n == query.len() {
+                end = i + c.len_utf8();
+                break;
+            }
+        }
+    }
+    if n < query.len() {
+        return None;
+    }
+
+    // walk back from there to find the shortest window
+    let mut start = end;
+    for (i, c) in line[..end].char_indices().rev() {
+        if chars_match(query[n - 1], c, ignore_case) {
+            n -= 1;
+            if n == 0 {
+                start = i;
+                break;
+            }
+        }
+    }
+
+    let mut score = 0;
+    let mut prev = line[..start].chars().next_back();
+    let mut prev_matched = false;
+    for c in line[start..end].chars() {
+        if n < query.len() && chars_match(query[n], c, ignore_case) {
+            n += 1;
+            score += SCORE_MATCH;
+            if prev_matched {
+                score += BONUS_CONSECUTIVE;
+            }
+            if prev.is_none_or(|p| !p.is_alphanumeric()) {
+                score += BONUS_WORD_START;
+            }
+            prev_matched = true;
+        } else {
+            score -= PENALTY_GAP;
+            prev_matched = false;
+        }
+        prev = Some(c);
+    }
+    Some(score)
+}
+
+/// A fuzzy history finder, listing the best matches for its query below
+/// the prompt.
+pub struct Picker {
+    query: String,
+    /// The number of distinct entries searched.
+    entries: usize,
+    /// All distinct entries matching the query, newest first.
+    candidates: Vec<usize>,
+    /// The candidates for shorter queries, with the query length in bytes.
+    narrowed: Vec<(usize, Vec<usize>)>,
+    /// The best matches, best first.
+    matches: Vec<usize>,
+    selected: usize,
+    scroll: usize,
+    rows: usize,
+}
+
+impl Picker {
+
+    pub fn new(history: &dyn HistoryStore, rows: usize) -> Picker {
+        // only list the newest of several identical entries
+        let mut seen = HashSet::new();
+        let candidates: Vec<usize> = history.iter().enumerate()
+            .filter(|(_, e)| seen.insert(e.line.clone()))
+            .map(|(idx, _)| idx)
+            .collect();
+        let mut picker = Picker {
+            query: String::new(),
+            entries: candidates.len(),
+            candidates: candidates,
+            narrowed: Vec::new(),
+            matches: Vec::new(),
+            selected: 0,
+            scroll: 0,
+            rows: rows,
+        };
+        picker.rank(history);
+        picker
+    }
+
+    /// Ranks the candidates by score, preferring newer entries on ties.
+    fn rank(&mut self, history: &dyn HistoryStore) {
+        let (query, ignore_case) = prepare_query(&self.query);
+        let mut scored: Vec<(i64, usize)> = self.candidates.iter()
+            .filter_map(|&idx| {
+                history.get(idx)
+                    .and_then(|e| score_chars(&query, ignore_case, &e.line))
+                    .map(|s| (s, idx))
+            })
+            .collect();
+        self.candidates = scored.iter().map(|&(_, idx)| idx).collect();
+        let best = |a: &(i64, usize), b: &(i64, usize)| -> Ordering {
+            b.0.cmp(&a.0).then(a.1.cmp(&b.1))
+        };
+        if scored.len() > MAX_MATCHES {
+            scored.select_nth_unstable_by(MAX_MATCHES, best);
+            scored.truncate(MAX_MATCHES);
+        }
+        scored.sort_unstable_by(best);
+        self.matches = scored.into_iter().map(|(_, idx)| idx).collect();
+        self.selected = 0;
+        self.scroll = 0;
+    }
+
+    pub fn insert(&mut self, text: &str, history: &dyn HistoryStore) {
+        // a longer query can only narrow down the current candidates
+        self.narrowed.push((self.query.len(), self.candidates.clone()));
+        self.query.push_str(text);
+        self.rank(history);
+    }
+
+    pub fn delete_char(&mut self, history: &dyn HistoryStore) {
+        if self.query.pop().is_none() {
+            return;
+        }
+        // go back to the candidates of the longest query that is not longer
+        while let Some((len, candidates)) = self.narrowed.pop() {
+            if len <= self.query.len() {
+                if len < self.query.len() {
+                    self.narrowed.push((len, candidates.clone()));
+                }
+                self.candidates = candidates;
+                break;
+            }
+        }
+        self.rank(history);
+    }
+
+    /// Selects the next better match.
+    pub fn up(&mut self) {
+        if self.selected > 0 {
+            self.selected -= 1;
+            if self.selected < self.scroll {
+                self.scroll = self.selected;
+            }
+        }
+    }
+
+    /// Selects the next worse match.
+    pub fn down(&mut self) {
+        if self.selected + 1 < self.matches.len() {
+            self.selected += 1;
+            if self.selected >= self.scroll + self.rows {
+                self.scroll = self.selected + 1 - self.rows;
+            }
+        }
+    }
+
+    /// The history index of the selected entry, if anything matches.
+    pub fn selected(&self) -> Option<usize> {
+        self.matches.get(self.selected).cloned()
+    }
+
+    /// Renders the query on the prompt line and the visible matches below
+    /// it, with the selected one inverted. Matches are cut off to fit the
+    /// width, so that each takes up a single row.
+    pub fn render(&self, history: &dyn HistoryStore, width: usize, clear: bool) -> Vec<u8> {
+        let mut line = Builder::new();
+        if clear {
+            line.clear_screen();
+        }
+        line.carriage_return();
+        let prompt = format!("(fuzzy-search {}/{}): ", self.candidates.len(), self.entries);
+        line.append(&prompt);
+        line.append(&self.query);
+        line.erase_below();
+        let visible = self.matches.iter().enumerate().skip(self.scroll).take(self.rows);
+        let mut shown = 0;
+        for (i, &idx) in visible {
+            let text = history.get(idx).map_or(String::new(), |e| {
+                truncate(&e.line.replace(char::is_control, " "), width.saturating_sub(1))
+            });
+            line.append("\r\n");
+            if i == self.selected {
+                line.invert_color();
+                line.append(&text);
+                line.reset_color();
+            } else {
+                line.append(&text);
+            }
+            shown += 1;
+        }
+        line.cursor_up(shown);
+        line.set_cursor_pos(UnicodeWidthStr::width(prompt.as_str()) + UnicodeWidthStr::width(self.query.as_str()));
+        line.build()
+    }
+}
+
+#[cfg(test)]
+use history::test_history;
+
+#[cfg(test)]
+fn score(query: &str, line: &str) -> Option<i64> {
+    let (query, ignore_case) = prepare_query(query);
+    score_chars(&query, ignore_case, line)
+}
+
+#[test]
+fn score_subsequence() {
+    assert!(score("gco", "git checkout").is_some());
+    assert!(score("GCO", "git checkout").is_none());
+    assert!(score("gcx", "git checkout").is_none());
+    assert_eq!(score("", "anything"), Some(0));
+    // consecutive and word start matches beat scattered ones
+    assert!(score("push", "git push") > score("push", "pause the usher"));
+    assert!(score("gc", "git commit") > score("gc", "ignore case"));
+}
+
+#[test]
+fn score_shortest_window() {
+    assert_eq!(score("ab", "a----ab"), score("ab", "ab"));
+}
+
+#[test]
+fn picker_ranking() {
+    let h = test_history(&["make test", "git commit", "make", "git checkout", "make"]);
+    let mut picker = Picker::new(&h, 2);
+    assert_eq!(picker.matches, vec![0, 1, 3, 4]);
+    picker.insert("mte", &h);
+    assert_eq!(picker.matches, vec![4]);
+    picker.delete_char(&h);
+    picker.delete_char(&h);
+    // matches at the start of a word rank first
+    assert_eq!(picker.matches, vec![0, 4, 3]);
+    picker.delete_char(&h);
+    picker.insert("gc", &h);
+    assert_eq!(picker.matches, vec![1, 3]);
+    picker.insert("h", &h);
+    assert_eq!(picker.selected(), Some(1));
+    picker.delete_char(&h);
+    picker.down();
+    picker.down();
+    assert_eq!(picker.selected(), Some(3));
+    picker.up();
+    assert_eq!(picker.selected(), Some(1));
+}
+
+#[test]
+fn picker_scrolls() {
+    let h = test_history(&["a1", "a2", "a3", "a4"]);
+    let mut picker = Picker::new(&h, 2);
+    picker.down();
+    picker.down();
+    picker.down();
+    assert_eq!((picker.selected, picker.scroll), (3, 2));
+    picker.up();
+    picker.up();
+    assert_eq!((picker.selected, picker.scroll), (1, 1));
+}
+
+#[test]
+fn picker_rows_fit_width() {
+    let h = test_history(&["short", "a line much too long for the terminal"]);
+    let picker = Picker::new(&h, 2);
+    let output = String::from_utf8(picker.render(&h, 30, false)).unwrap();
+    assert_eq!(output, "\r(fuzzy-search 2/2): \x1b[0J\r\n\x1b[7ma line much too long for the…\x1b[0m\r\nshort\x1b[2A\r\x1b[20C");
+}
+
+#[test]
+fn picker_large_history() {
+    let mut h = ::history::MemoryHistory::new();
+    for i in 0..100000 {
+        h.push(::history::HistoryEntry::new(format!("git commit -m 'change number {}' --author someone", i)));
+    }
+    let mut picker = Picker::new(&h, 10);
+    for c in ["c", "h", "9", "9"].iter() {
+        picker.insert(c, &h);
+    }
+    picker.delete_char(&h);
+    assert!(picker.selected().is_some());
+    // only the best matches are sorted, but the prompt counts all of them
+    assert_eq!(picker.matches.len(), MAX_MATCHES);
+    let output = String::from_utf8(picker.render(&h, 80, false)).unwrap();
+    assert!(output.starts_with("\r(fuzzy-search 40951/100000): ch9"));
+}
@@ -0,0 +1,71 @@
+use std::ops::Range;
+
+use style::Style;
+
+/// A part of the line drawn in a style.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Span {
+    /// The byte range of the line.
+    pub range: Range<usize>,
+    pub style: Style
+}
+
+impl Span {
+    pub fn new(range: Range<usize>, style: Style) -> Span {
+        Span { range: range, style: style }
+    }
+}
+
+/// Colours the line, asked again after every edit.
+pub trait Highlighter {
+    /// Returns the styled parts of the line. Later spans take precedence
+    /// over earlier ones where they overlap.
+    fn highlight(&self, line: &str) -> Vec<Span>;
+}
+
+impl<F> Highlighter for F where F: Fn(&str) -> Vec<Span> {
+    fn highlight(&self, line: &str) -> Vec<Span> {
+        self(line)
+    }
+}
+
+/// Returns the line with the escape sequences drawing its spans.
+pub fn paint(line: &str, spans: &[Span]) -> String {
+    if spans.is_empty() || line.is_empty() {
+        return line.to_owned();
+    }
+    let mut styles = vec![Style::new(); line.len()];
+    for span in spans {
+        let end = span.range.end.min(line.len());
+        if span.range.start < end {
+            for style in &mut styles[span.range.start..end] {
+                *style = span.style;
+            }
+        }
+    }
+    let mut s = String::with_capacity(line.len());
+    let mut start = 0;
+    let ends = line.char_indices().map(|(i, _)| i).skip(1).chain(Some(line.len()));
+    // a run ends where the style of the next character differs
+    for i in ends {
+        if i == line.len() || styles[i] != styles[start] {
+            s.push_str(&styles[start].paint(&line[start..i]));
+            start = i;
+        }
+    }
+    s
+}
+
+#[test]
+fn paint_spans() {
+    let bold = Style { bold: true, ..Style::new() };
+    let red = Style::fg(::style::Color::Red);
+    assert_eq!(paint("select 1", &[]), "select 1");
+    assert_eq!(paint("select 1", &[Span::new(0..6, bold)]), "\x1b[1mselect\x1b[0m 1");
+    assert_eq!(paint("a 'ü' b", &[Span::new(2..6, red), Span::new(3..5, bold)]),
+               "a \x1b[31m'\x1b[0m\x1b[1mü\x1b[0m\x1b[31m'\x1b[0m b");
+    // ranges are clamped to the line and may fall into characters
+    assert_eq!(paint("ab", &[Span::new(1..9, bold)]), "a\x1b[1mb\x1b[0m");
+    assert_eq!(paint("ü", &[Span::new(1..2, bold)]), "ü");
+    assert_eq!(paint("", &[Span::new(0..2, bold)]), "");
+}
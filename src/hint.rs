@@ -0,0 +1,57 @@
+use history::HistoryStore;
+
+/// A source of suggestions, drawn dimmed after the cursor while the line
+/// is typed.
+pub trait Hinter {
+    /// Returns the text suggested to follow the line, if any.
+    fn hint(&self, line: &str, history: &dyn HistoryStore) -> Option<String>;
+}
+
+impl<F> Hinter for F where F: Fn(&str, &dyn HistoryStore) -> Option<String> {
+    fn hint(&self, line: &str, history: &dyn HistoryStore) -> Option<String> {
+        self(line, history)
+    }
+}
+
+/// Suggests the rest of the most recent history entry starting with the
+/// line, like fish.
+pub struct HistoryHinter;
+
+impl Hinter for HistoryHinter {
+    fn hint(&self, line: &str, history: &dyn HistoryStore) -> Option<String> {
+        if line.is_empty() {
+            return None;
+        }
+        history.iter()
+            .find(|e| e.line.len() > line.len() && e.line.starts_with(line))
+            .map(|e| e.line[line.len()..].to_owned())
+    }
+}
+
+/// Returns the start of a hint up to the end of its first word.
+pub fn first_word(hint: &str) -> &str {
+    let start = hint.find(char::is_alphanumeric).unwrap_or(hint.len());
+    let end = hint[start..].find(|c: char| !c.is_alphanumeric()).map_or(hint.len(), |n| start + n);
+    &hint[..end]
+}
+
+#[test]
+fn history_hints() {
+    use history::{HistoryEntry, MemoryHistory};
+    let mut h = MemoryHistory::new();
+    for line in ["git commit", "git checkout master", "ls"].iter() {
+        h.push(HistoryEntry::new(line.to_string()));
+    }
+    assert_eq!(HistoryHinter.hint("git c", &h), Some("heckout master".to_owned()));
+    assert_eq!(HistoryHinter.hint("git co", &h), Some("mmit".to_owned()));
+    assert_eq!(HistoryHinter.hint("ls", &h), None);
+    assert_eq!(HistoryHinter.hint("", &h), None);
+}
+
+#[test]
+fn hint_words() {
+    assert_eq!(first_word("heckout master"), "heckout");
+    assert_eq!(first_word(" master"), " master");
+    assert_eq!(first_word("/src/lib.rs"), "/src");
+    assert_eq!(first_word("  "), "  ");
+}
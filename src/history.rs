@@ -1,14 +1,65 @@
-use std::collections::VecDeque;
-use std::ops::Index;
+use std::borrow::{Borrow, Cow};
+use std::collections::{BTreeMap, VecDeque};
+use std::cmp::min;
+use std::env;
+use std::usize;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write, Seek, SeekFrom};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::str;
+
+use nix::fcntl::{flock, FlockArg};
+
+use error::Error;
+
+/// A line in the history, along with information about when and where it was entered.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistoryEntry {
+    pub line: String,
+    /// When the line was entered.
+    pub timestamp: Option<SystemTime>,
+    /// How long running the line took, as reported by the host.
+    pub duration: Option<Duration>,
+    /// Exit status of running the line, as reported by the host.
+    pub exit_status: Option<i32>,
+    /// Working directory the line was entered in.
+    pub cwd: Option<PathBuf>,
+    /// Identifies the session the line was entered in.
+    pub session: Option<String>,
+    /// Free-form key/value pairs.
+    pub tags: BTreeMap<String, String>
+}
+
+impl HistoryEntry {
+    /// Creates an entry without any metadata.
+    pub fn new(line: String) -> HistoryEntry {
+        HistoryEntry {
+            line: line,
+            timestamp: None,
+            duration: None,
+            exit_status: None,
+            cwd: None,
+            session: None,
+            tags: BTreeMap::new()
+        }
+    }
+
+    fn has_metadata(&self) -> bool {
+        self.timestamp.is_some() || self.duration.is_some() || self.exit_status.is_some() ||
+            self.cwd.is_some() || self.session.is_some() || !self.tags.is_empty()
+    }
+}
 
 pub struct Cursor<'a> {
-    history: &'a History,
+    history: &'a dyn HistoryStore,
     cur: Option<usize>
 }
 
 impl<'a> Cursor<'a> {
 
-    pub fn new(h: &'a History) -> Cursor<'a> {
+    pub fn new(h: &'a dyn HistoryStore) -> Cursor<'a> {
         Cursor { history: h, cur: None }
     }
 
@@ -17,7 +68,7 @@ impl<'a> Cursor<'a> {
         self.cur = match self.cur {
             Some(i) if i + 1 < self.history.len() => Some(i + 1),
             Some(i) => Some(i),
-            None if self.history.len() > 0 => {flag = true; Some(0)},
+            None if !self.history.is_empty() => {flag = true; Some(0)},
             None => None
         };
         flag
@@ -33,56 +84,1083 @@ impl<'a> Cursor<'a> {
         flag
     }
 
-    pub fn get(&self) -> Option<&'a String> {
+    /// Moves to the next older entry that starts with `prefix` and differs from `current`.
+    ///
+    /// Returns true if the cursor was not pointing into the history before.
+    pub fn incr_prefix(&mut self, prefix: &str, current: &str) -> bool {
+        let start = self.cur.map_or(0, |i| i + 1);
+        let history = self.history;
+        let found = (start..history.len()).find(|&i| {
+            history.get(i).is_some_and(|e| e.line.starts_with(prefix) && e.line != current)
+        });
+        match found {
+            Some(i) => {
+                let flag = self.cur.is_none();
+                self.cur = Some(i);
+                flag
+            },
+            None => false
+        }
+    }
+
+    /// Moves to the next newer entry that starts with `prefix` and differs from `current`.
+    ///
+    /// Returns true if there is no such entry and the cursor leaves the history.
+    pub fn decr_prefix(&mut self, prefix: &str, current: &str) -> bool {
+        let end = match self.cur {
+            Some(i) => i,
+            None => return false
+        };
+        let history = self.history;
+        let found = (0..end).rev().find(|&i| {
+            history.get(i).is_some_and(|e| e.line.starts_with(prefix) && e.line != current)
+        });
+        self.cur = found;
+        found.is_none()
+    }
+
+    pub fn get(&self) -> Option<Cow<'a, str>> {
         match self.cur {
             None => None,
-            Some(i) => self.history.get(i)
+            Some(i) => self.history.get(i).map(line_of)
+        }
+    }
+
+    pub fn position(&self) -> Option<usize> {
+        self.cur
+    }
+
+    /// Moves the cursor back to the line being edited.
+    ///
+    /// Returns true if the cursor was pointing into the history before.
+    pub fn reset(&mut self) -> bool {
+        self.cur.take().is_some()
+    }
+
+    /// Points the cursor at the entry with the given index.
+    ///
+    /// Returns true if the cursor was not pointing into the history before.
+    pub fn set(&mut self, idx: usize) -> bool {
+        let flag = self.cur.is_none();
+        self.cur = Some(idx);
+        flag
+    }
+
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SearchDirection {
+    /// Towards older entries.
+    Reverse,
+    /// Towards newer entries.
+    Forward
+}
+
+/// Storage for history entries, most recent entry first.
+///
+/// `MemoryHistory` is used by default; implement this trait to keep the
+/// history elsewhere, e.g. in a database. Entries are handed out as `Cow`,
+/// so a store does not need to keep them in memory. Limits, duplicate
+/// policies and history files are handled on top of the store.
+pub trait HistoryStore {
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn get(&self, idx: usize) -> Option<Cow<'_, HistoryEntry>>;
+
+    /// Replaces an entry, if the store supports modifying entries.
+    ///
+    /// Returns false if it does not or there is no such entry.
+    fn update(&mut self, _idx: usize, _entry: HistoryEntry) -> bool {
+        false
+    }
+
+    /// Adds an entry as the most recent one.
+    fn push(&mut self, entry: HistoryEntry);
+
+    fn remove(&mut self, idx: usize) -> Option<HistoryEntry>;
+
+    fn clear(&mut self) {
+        while self.remove(0).is_some() {}
+    }
+
+    /// Finds the first entry containing `query`, starting at index `start` and
+    /// moving in the given direction.
+    ///
+    /// Returns the index of the entry along with the byte offset of the match,
+    /// which is the last occurrence when searching in reverse.
+    fn search(&self, query: &str, start: usize, dir: SearchDirection) -> Option<(usize, usize)> {
+        if query.is_empty() || start >= self.len() {
+            return None;
+        }
+        let matches = |idx: usize| {
+            self.get(idx).and_then(|entry| {
+                match dir {
+                    SearchDirection::Reverse => entry.line.rfind(query),
+                    SearchDirection::Forward => entry.line.find(query)
+                }
+            }).map(|offset| (idx, offset))
+        };
+        match dir {
+            SearchDirection::Reverse => (start..self.len()).filter_map(matches).next(),
+            SearchDirection::Forward => (0..start + 1).rev().filter_map(matches).next()
         }
     }
 
+    /// Iterates over all entries, most recent first.
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item=Cow<'a, HistoryEntry>> + 'a> {
+        Box::new((0..self.len()).filter_map(move |idx| self.get(idx)))
+    }
+}
+
+/// Returns the line of an entry, borrowing it if the entry is borrowed.
+pub fn line_of(entry: Cow<'_, HistoryEntry>) -> Cow<'_, str> {
+    match entry {
+        Cow::Borrowed(e) => Cow::Borrowed(&e.line),
+        Cow::Owned(e) => Cow::Owned(e.line)
+    }
 }
 
+/// Keeps the history in memory.
+#[derive(Default)]
+pub struct MemoryHistory {
+    deque: VecDeque<HistoryEntry>
+}
+
+impl MemoryHistory {
+    pub fn new() -> MemoryHistory {
+        MemoryHistory { deque: VecDeque::new() }
+    }
+}
+
+impl HistoryStore for MemoryHistory {
+    fn len(&self) -> usize {
+        self.deque.len()
+    }
+
+    fn get(&self, idx: usize) -> Option<Cow<'_, HistoryEntry>> {
+        self.deque.get(idx).map(Cow::Borrowed)
+    }
+
+    fn update(&mut self, idx: usize, entry: HistoryEntry) -> bool {
+        match self.deque.get_mut(idx) {
+            Some(e) => {
+                *e = entry;
+                true
+            },
+            None => false
+        }
+    }
+
+    fn push(&mut self, entry: HistoryEntry) {
+        self.deque.push_front(entry)
+    }
+
+    fn remove(&mut self, idx: usize) -> Option<HistoryEntry> {
+        self.deque.remove(idx)
+    }
+
+    fn clear(&mut self) {
+        self.deque.clear()
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item=Cow<'a, HistoryEntry>> + 'a> {
+        Box::new(self.deque.iter().map(Cow::Borrowed))
+    }
+}
+
+/// How a new line is treated when it is already present in the history.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum HistoryDuplicates {
+    /// Skip the line if it equals the most recent entry.
+    IgnoreConsecutive,
+    /// Skip the line if it equals any entry.
+    IgnoreAll,
+    /// Remove all older entries that equal the line before adding it.
+    EraseOlder
+}
+
+/// Limits and filters applied to lines as they are added to the history,
+/// either interactively or when loading a history file.
+pub struct HistoryPolicy {
+    /// Maximum number of entries, evicting the oldest ones first.
+    pub max_len: Option<usize>,
+    /// Maximum total size of all entries in bytes, evicting the oldest ones first.
+    pub max_bytes: Option<usize>,
+    pub duplicates: HistoryDuplicates,
+    /// Skip lines starting with a space.
+    pub ignore_space: bool,
+    /// Skip lines matching any of these patterns, where `*` matches any
+    /// sequence of characters and `?` matches a single character.
+    pub ignore_patterns: Vec<String>
+}
+
+impl HistoryPolicy {
+    pub fn default() -> HistoryPolicy {
+        HistoryPolicy {
+            max_len: None,
+            max_bytes: None,
+            duplicates: HistoryDuplicates::IgnoreConsecutive,
+            ignore_space: false,
+            ignore_patterns: Vec::new()
+        }
+    }
+
+    fn ignores(&self, s: &str) -> bool {
+        (self.ignore_space && s.starts_with(' ')) ||
+            self.ignore_patterns.iter().any(|p| glob_match(p, s))
+    }
+}
+
+/// A history file shared with other processes.
+struct SharedFile {
+    path: PathBuf,
+    // position up to which the file has been read or written by this process
+    offset: u64
+}
+
+/// The history of a `Copperline`, applying the policy and handling history
+/// files on top of the store.
 pub struct History {
-    deque: VecDeque<String>
+    store: Box<dyn HistoryStore>,
+    shared: Option<SharedFile>,
+    // session recorded with every new entry
+    session: Option<String>,
+    policy: HistoryPolicy,
+    // total size of all entries in bytes
+    bytes: usize,
+    // number of most recent entries that have not been written to a file yet
+    unsaved: usize
 }
 
 impl History {
 
     pub fn new() -> History {
         History {
-            deque: VecDeque::new()
+            store: Box::new(MemoryHistory::new()),
+            shared: None,
+            session: None,
+            policy: HistoryPolicy::default(),
+            bytes: 0,
+            unsaved: 0
         }
     }
 
+    pub fn store(&self) -> &dyn HistoryStore {
+        &*self.store
+    }
+
+    /// Replaces the store, treating the entries it already holds as saved,
+    /// and evicts entries exceeding the limits of the policy.
+    pub fn set_store(&mut self, store: Box<dyn HistoryStore>) {
+        self.bytes = store.iter().map(|e| e.line.len()).sum();
+        self.store = store;
+        self.unsaved = 0;
+        self.evict();
+    }
+
+    /// Replaces the policy and evicts entries exceeding the new limits.
+    pub fn set_policy(&mut self, policy: HistoryPolicy) {
+        self.policy = policy;
+        self.evict();
+    }
+
+    pub fn set_session(&mut self, session: Option<String>) {
+        self.session = session;
+    }
+
     pub fn len(&self) -> usize {
-        self.deque.len()
+        self.store.len()
     }
 
+    /// Adds a line, recording the current time, working directory and session.
     pub fn push(&mut self, s: String) {
-        if !s.is_empty() && self.deque.front() != Option::Some(&s) {
-            self.deque.push_front(s)
+        let mut entry = HistoryEntry::new(s);
+        entry.timestamp = Some(SystemTime::now());
+        entry.cwd = env::current_dir().ok();
+        entry.session = self.session.clone();
+        self.push_entry(entry)
+    }
+
+    pub fn push_entry(&mut self, entry: HistoryEntry) {
+        if entry.line.is_empty() || self.policy.ignores(&entry.line) {
+            return;
         }
+        match self.policy.duplicates {
+            HistoryDuplicates::IgnoreConsecutive
+                if self.store.get(0).is_some_and(|e| e.line == entry.line) => return,
+            HistoryDuplicates::IgnoreAll
+                if self.store.iter().any(|e| e.line == entry.line) => return,
+            HistoryDuplicates::EraseOlder => {
+                loop {
+                    let found = self.store.iter().position(|e| e.line == entry.line);
+                    match found {
+                        Some(idx) => self.remove(idx),
+                        None => break
+                    };
+                }
+            },
+            _ => {}
+        }
+        self.bytes += entry.line.len();
+        self.store.push(entry);
+        self.unsaved += 1;
+        self.evict();
+    }
+
+    /// Removes the oldest entries until the history fits within the limits of the policy.
+    fn evict(&mut self) {
+        let max_len = self.policy.max_len.unwrap_or(usize::MAX);
+        let max_bytes = self.policy.max_bytes.unwrap_or(usize::MAX);
+        while self.store.len() > max_len || self.bytes > max_bytes {
+            let last = self.store.len().wrapping_sub(1);
+            match self.store.remove(last) {
+                Some(e) => self.bytes -= e.line.len(),
+                None => break
+            }
+        }
+        self.unsaved = min(self.unsaved, self.store.len());
+    }
+
+    pub fn get(&self, idx: usize) -> Option<Cow<'_, str>> {
+        self.get_entry(idx).map(line_of)
+    }
+
+    pub fn get_entry(&self, idx: usize) -> Option<Cow<'_, HistoryEntry>> {
+        self.store.get(idx)
+    }
+
+    /// Records the exit status of running the line of an entry.
+    ///
+    /// Returns false if there is no such entry or the store does not support
+    /// modifying entries.
+    pub fn set_exit_status(&mut self, idx: usize, status: i32) -> bool {
+        self.update_entry(idx, |e| e.exit_status = Some(status))
+    }
+
+    /// Records how long running the line of an entry took.
+    pub fn set_duration(&mut self, idx: usize, duration: Duration) -> bool {
+        self.update_entry(idx, |e| e.duration = Some(duration))
+    }
+
+    /// Sets a tag of an entry, replacing its previous value.
+    pub fn set_tag(&mut self, idx: usize, key: String, value: String) -> bool {
+        self.update_entry(idx, |e| { e.tags.insert(key, value); })
+    }
+
+    /// Modifies the metadata of an entry, leaving its line as is.
+    fn update_entry<F: FnOnce(&mut HistoryEntry)>(&mut self, idx: usize, f: F) -> bool {
+        let mut entry = match self.store.get(idx) {
+            Some(entry) => entry.into_owned(),
+            None => return false
+        };
+        f(&mut entry);
+        self.store.update(idx, entry)
+    }
+
+    /// Iterates over all entries, most recent first.
+    pub fn entries<'a>(&'a self) -> Box<dyn Iterator<Item=Cow<'a, HistoryEntry>> + 'a> {
+        self.store.iter()
     }
 
-    pub fn pop(&mut self) -> Option<String> {
-        self.deque.pop_front()
+    pub fn remove(&mut self, idx: usize) -> Option<HistoryEntry> {
+        let e = self.store.remove(idx);
+        if let Some(ref e) = e {
+            self.bytes -= e.line.len();
+            if idx < self.unsaved {
+                self.unsaved -= 1;
+            }
+        }
+        e
+    }
+
+    pub fn clear(&mut self) {
+        self.store.clear();
+        self.bytes = 0;
+        self.unsaved = 0;
     }
 
-    pub fn get(&self, idx: usize) -> Option<&String> {
-        let len = self.len();
-        if len > 0 && idx < len {
-            Some(self.deque.index(idx))
+    /// Reads the entries from the file at `path` and adds them to the history.
+    ///
+    /// The whole file is validated before any entry is added, so a corrupt
+    /// file leaves the history untouched.
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
+        let mut bytes = Vec::new();
+        let mut file = try!(File::open(path));
+        try!(lock_file(&file, FlockArg::LockShared));
+        try!(file.read_to_end(&mut bytes));
+        let entries = try!(parse_entries(&bytes));
+        self.push_saved(entries);
+        Ok(())
+    }
+
+    /// Writes all entries to the file at `path`, replacing its contents.
+    pub fn save<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
+        // truncate only once the lock is held, so readers never see a partial file
+        let mut file = try!(OpenOptions::new().write(true).create(true).truncate(false).open(&path));
+        try!(lock_file(&file, FlockArg::LockExclusive));
+        try!(file.set_len(0));
+        try!(file.write_all(&format_entries(self.store.iter())));
+        self.unsaved = 0;
+        // the file now holds every entry, so the next sync only reads what follows
+        if self.is_shared(path.as_ref()) {
+            let len = try!(file.metadata()).len();
+            if let Some(ref mut shared) = self.shared {
+                shared.offset = len;
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends the entries added since the last load, save or append to the file at `path`.
+    ///
+    /// Appending to the shared history file syncs with it instead.
+    pub fn append<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
+        if self.is_shared(path.as_ref()) {
+            return self.sync();
+        }
+        let mut file = try!(OpenOptions::new().append(true).create(true).open(path));
+        try!(lock_file(&file, FlockArg::LockExclusive));
+        try!(file.write_all(&format_entries(self.store.iter().take(self.unsaved))));
+        self.unsaved = 0;
+        Ok(())
+    }
+
+    /// Shares the history with other processes through the file at `path`,
+    /// adding the entries it already contains.
+    pub fn share<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
+        self.shared = Some(SharedFile { path: path.as_ref().to_path_buf(), offset: 0 });
+        self.sync()
+    }
+
+    /// Returns whether `path` names the shared history file.
+    fn is_shared(&self, path: &Path) -> bool {
+        match self.shared {
+            Some(ref shared) => shared.path == path || match (fs::canonicalize(&shared.path), fs::canonicalize(path)) {
+                (Ok(a), Ok(b)) => a == b,
+                _ => false
+            },
+            None => false
+        }
+    }
+
+    /// Exchanges entries with the shared history file, if any.
+    ///
+    /// Appends the entries added since the last sync and adds the entries
+    /// other processes appended in the meantime. The file stays locked for
+    /// the whole exchange and every batch is written at once, so entries of
+    /// concurrent sessions never interleave. While the file ends in an
+    /// incomplete line, nothing is appended.
+    pub fn sync(&mut self) -> Result<(), Error> {
+        let (path, offset) = match self.shared {
+            Some(ref shared) => (shared.path.clone(), shared.offset),
+            None => return Ok(())
+        };
+        let mut file = try!(OpenOptions::new().read(true).append(true).create(true).open(&path));
+        try!(lock_file(&file, FlockArg::LockExclusive));
+        let len = try!(file.metadata()).len();
+        // start over if the file has been truncated since
+        let offset = if len < offset { 0 } else { offset };
+        let mut bytes = Vec::new();
+        try!(file.seek(SeekFrom::Start(offset)));
+        try!(file.read_to_end(&mut bytes));
+        // skip a trailing incomplete line left behind by a writer that did not
+        // lock the file, reading it again once it is complete
+        let complete = bytes.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+        let entries = try!(parse_entries(&bytes[..complete]));
+        let mut offset = offset + complete as u64;
+        // new entries would be glued to the incomplete line, so they wait for the next sync
+        if complete == bytes.len() {
+            let batch = format_entries(self.store.iter().take(self.unsaved));
+            try!(file.write_all(&batch));
+            offset += batch.len() as u64;
+            self.unsaved = 0;
+        }
+        self.push_saved(entries);
+        if let Some(ref mut shared) = self.shared {
+            shared.offset = offset;
+        }
+        Ok(())
+    }
+
+    /// Adds entries that are already stored in a file.
+    ///
+    /// Entries not written to a file yet stay the most recent ones, so that
+    /// they are still written by the next append.
+    fn push_saved(&mut self, entries: Vec<HistoryEntry>) {
+        let mut unsaved = Vec::with_capacity(self.unsaved);
+        while self.unsaved > 0 {
+            match self.remove(0) {
+                Some(e) => unsaved.push(e),
+                None => break
+            }
+        }
+        for entry in entries {
+            self.push_entry(entry);
+        }
+        self.unsaved = 0;
+        for entry in unsaved.into_iter().rev() {
+            self.push_entry(entry);
+        }
+    }
+
+}
+
+fn lock_file(file: &File, arg: FlockArg) -> Result<(), Error> {
+    try!(flock(file.as_raw_fd(), arg));
+    Ok(())
+}
+
+/// Matches `s` against a pattern where `*` matches any sequence of
+/// characters and `?` matches a single character.
+fn glob_match(pattern: &str, s: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let s: Vec<char> = s.chars().collect();
+    let (mut pi, mut si) = (0, 0);
+    // position of the last `*` in the pattern and the input position it was tried at
+    let mut star: Option<(usize, usize)> = None;
+    while si < s.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == s[si]) {
+            pi += 1;
+            si += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi, si));
+            pi += 1;
+        } else if let Some((star_pi, star_si)) = star {
+            // let the last `*` swallow one more character and retry
+            pi = star_pi + 1;
+            si = star_si + 1;
+            star = Some((star_pi, star_si + 1));
         } else {
-            None
+            return false;
         }
     }
+    p[pi..].iter().all(|&c| c == '*')
+}
 
-    pub fn remove(&mut self, idx: usize) -> Option<String> {
-        self.deque.remove(idx)
+/// Escapes `s` so that it fits on a single line of the history file,
+/// additionally escaping the characters in `special`.
+fn escape(s: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if special.contains(&c) => {
+                escaped.push('\\');
+                escaped.push(c);
+            },
+            c    => escaped.push(c)
+        }
     }
+    escaped
+}
 
-    pub fn clear(&mut self) {
-        self.deque.clear()
+fn escape_entry(s: &str) -> String {
+    // a leading `#` would start a metadata line
+    if s.starts_with('#') {
+        format!("\\{}", escape(s, &[]))
+    } else {
+        escape(s, &[])
+    }
+}
+
+fn escape_field(s: &str) -> String {
+    escape(s, &['='])
+}
+
+/// Reverses `escape`, returning `None` for malformed escape sequences.
+fn unescape(s: &str) -> Option<String> {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('\\') => unescaped.push('\\'),
+                Some('n')  => unescaped.push('\n'),
+                Some('r')  => unescaped.push('\r'),
+                Some('t')  => unescaped.push('\t'),
+                Some('#')  => unescaped.push('#'),
+                Some('=')  => unescaped.push('='),
+                _          => return None
+            }
+        } else {
+            unescaped.push(c);
+        }
+    }
+    Some(unescaped)
+}
+
+fn millis(d: Duration) -> u64 {
+    d.as_secs() * 1000 + d.subsec_millis() as u64
+}
+
+/// Formats the metadata of an entry as tab separated `key=value` fields.
+fn format_metadata(entry: &HistoryEntry) -> String {
+    let mut fields = Vec::new();
+    if let Some(time) = entry.timestamp.and_then(|t| t.duration_since(UNIX_EPOCH).ok()) {
+        fields.push(format!("time={}", millis(time)));
+    }
+    if let Some(duration) = entry.duration {
+        fields.push(format!("duration={}", millis(duration)));
+    }
+    if let Some(status) = entry.exit_status {
+        fields.push(format!("status={}", status));
+    }
+    if let Some(ref cwd) = entry.cwd {
+        fields.push(format!("cwd={}", escape_field(&cwd.to_string_lossy())));
+    }
+    if let Some(ref session) = entry.session {
+        fields.push(format!("session={}", escape_field(session)));
+    }
+    for (key, value) in entry.tags.iter() {
+        fields.push(format!("tag.{}={}", escape_field(key), escape_field(value)));
+    }
+    fields.join("\t")
+}
+
+/// Splits a field at the first unescaped `=`.
+fn split_field(field: &str) -> Option<(&str, &str)> {
+    let mut escaped = false;
+    for (i, c) in field.char_indices() {
+        match c {
+            '=' if !escaped => return Some((&field[..i], &field[i + 1..])),
+            '\\' => escaped = !escaped,
+            _ => escaped = false
+        }
+    }
+    None
+}
+
+/// Parses a metadata line into an entry without a line, ignoring unknown keys.
+fn parse_metadata(s: &str) -> Result<HistoryEntry, ()> {
+    let mut entry = HistoryEntry::new(String::new());
+    for field in s.split('\t') {
+        let (key, value) = try!(split_field(field).ok_or(()));
+        match key {
+            "time" => {
+                let ms = try!(value.parse().map_err(|_| ()));
+                entry.timestamp = Some(UNIX_EPOCH + Duration::from_millis(ms));
+            },
+            "duration" => {
+                let ms = try!(value.parse().map_err(|_| ()));
+                entry.duration = Some(Duration::from_millis(ms));
+            },
+            "status" => entry.exit_status = Some(try!(value.parse().map_err(|_| ()))),
+            "cwd" => entry.cwd = Some(PathBuf::from(try!(unescape(value).ok_or(())))),
+            "session" => entry.session = Some(try!(unescape(value).ok_or(()))),
+            _ if key.starts_with("tag.") => {
+                let key = try!(unescape(&key["tag.".len()..]).ok_or(()));
+                entry.tags.insert(key, try!(unescape(value).ok_or(())));
+            },
+            _ => {}
+        }
+    }
+    Ok(entry)
+}
+
+/// Formats entries given most recent first, writing the oldest entry first.
+fn format_entries<E: Borrow<HistoryEntry>, I: Iterator<Item=E>>(entries: I) -> Vec<u8> {
+    let entries: Vec<_> = entries.collect();
+    let mut bytes = Vec::new();
+    for entry in entries.iter().rev() {
+        let entry = entry.borrow();
+        if entry.has_metadata() {
+            bytes.push(b'#');
+            bytes.extend(format_metadata(entry).as_bytes());
+            bytes.push(b'\n');
+        }
+        bytes.extend(escape_entry(&entry.line).as_bytes());
+        bytes.push(b'\n');
+    }
+    bytes
+}
+
+/// Parses the contents of a history file, oldest entry first.
+///
+/// Each entry is on its own line, optionally preceded by a line starting
+/// with `#` that holds its metadata.
+fn parse_entries(bytes: &[u8]) -> Result<Vec<HistoryEntry>, Error> {
+    let mut entries = Vec::new();
+    let mut metadata = None;
+    let mut lines = bytes.split(|&b| b == b'\n').enumerate().peekable();
+    let mut last = 0;
+    while let Some((idx, line)) = lines.next() {
+        // the final newline leaves an empty slice behind
+        if line.is_empty() && lines.peek().is_none() {
+            break;
+        }
+        last = idx + 1;
+        let text = try!(str::from_utf8(line).map_err(|_| Error::InvalidHistory(idx + 1)));
+        if let Some(fields) = text.strip_prefix('#') {
+            // two metadata lines in a row
+            if metadata.is_some() {
+                return Err(Error::InvalidHistory(idx + 1));
+            }
+            metadata = Some(try!(parse_metadata(fields).map_err(|_| Error::InvalidHistory(idx + 1))));
+        } else {
+            let mut entry = metadata.take().unwrap_or_else(|| HistoryEntry::new(String::new()));
+            entry.line = try!(unescape(text).ok_or(Error::InvalidHistory(idx + 1)));
+            entries.push(entry);
+        }
     }
+    // metadata without an entry
+    if metadata.is_some() {
+        return Err(Error::InvalidHistory(last));
+    }
+    Ok(entries)
+}
+
+/// Returns a history of `lines`, the last one being the most recent.
+#[cfg(test)]
+pub fn test_history(lines: &[&str]) -> MemoryHistory {
+    let mut h = MemoryHistory::new();
+    for line in lines {
+        h.push(HistoryEntry::new(line.to_string()));
+    }
+    h
+}
+
+#[test]
+fn search_both_directions() {
+    let mut h = History::new();
+    h.push("git commit".to_string());
+    h.push("ls".to_string());
+    h.push("git push git".to_string());
+    assert_eq!(h.store().search("git", 0, SearchDirection::Reverse), Some((0, 9)));
+    assert_eq!(h.store().search("git", 1, SearchDirection::Reverse), Some((2, 0)));
+    assert_eq!(h.store().search("git", 2, SearchDirection::Forward), Some((2, 0)));
+    assert_eq!(h.store().search("git", 1, SearchDirection::Forward), Some((0, 0)));
+    assert_eq!(h.store().search("svn", 0, SearchDirection::Reverse), None);
+    assert_eq!(h.store().search("", 0, SearchDirection::Reverse), None);
+}
+
+#[test]
+fn custom_store() {
+    // keeps the oldest entry first, hands out copies and relies on the provided methods
+    struct VecStore(Vec<HistoryEntry>);
+
+    impl HistoryStore for VecStore {
+        fn len(&self) -> usize {
+            self.0.len()
+        }
+        fn get(&self, idx: usize) -> Option<Cow<'_, HistoryEntry>> {
+            self.0.len().checked_sub(idx + 1).and_then(|i| self.0.get(i)).cloned().map(Cow::Owned)
+        }
+        fn push(&mut self, entry: HistoryEntry) {
+            self.0.push(entry)
+        }
+        fn remove(&mut self, idx: usize) -> Option<HistoryEntry> {
+            match self.0.len().checked_sub(idx + 1) {
+                Some(i) => Some(self.0.remove(i)),
+                None => None
+            }
+        }
+    }
+
+    let mut h = History::new();
+    h.push("lost".to_string());
+    h.set_store(Box::new(VecStore(vec![HistoryEntry::new("old".to_string())])));
+    h.set_policy(HistoryPolicy {
+        max_len: Some(3),
+        duplicates: HistoryDuplicates::EraseOlder,
+        ..HistoryPolicy::default()
+    });
+    for line in ["a", "b", "a", "c"].iter() {
+        h.push(line.to_string());
+    }
+    let lines: Vec<_> = h.entries().map(|e| e.line.clone()).collect();
+    assert_eq!(lines, vec!["c", "a", "b"]);
+    assert_eq!(h.store().search("b", 0, SearchDirection::Reverse), Some((2, 0)));
+    assert!(!h.set_exit_status(0, 1));
+    h.clear();
+    assert!(h.store().is_empty());
+}
+
+#[test]
+fn set_metadata() {
+    let mut h = History::new();
+    h.push("make".to_string());
+    assert!(h.set_exit_status(0, 2));
+    assert!(h.set_duration(0, Duration::from_millis(1500)));
+    assert!(h.set_tag(0, "host".to_string(), "build1".to_string()));
+    assert!(!h.set_exit_status(1, 0));
+    let entry = h.get_entry(0).unwrap();
+    assert_eq!(entry.line, "make");
+    assert_eq!(entry.exit_status, Some(2));
+    assert_eq!(entry.duration, Some(Duration::from_millis(1500)));
+    assert_eq!(entry.tags.get("host"), Some(&"build1".to_string()));
+}
+
+#[test]
+fn cursor_prefix() {
+    let mut h = History::new();
+    h.push("git commit".to_string());
+    h.push("ls".to_string());
+    h.push("git push".to_string());
+    h.push("git push".to_string());
+    let mut c = Cursor::new(h.store());
+    assert!(!c.decr_prefix("git", "git"));
+    assert!(c.incr_prefix("git", "git"));
+    assert_eq!(c.get().as_deref(), Some("git push"));
+    assert!(!c.incr_prefix("git", "git push"));
+    assert_eq!(c.get().as_deref(), Some("git commit"));
+    assert!(!c.incr_prefix("git", "git commit"));
+    assert_eq!(c.get().as_deref(), Some("git commit"));
+    assert!(!c.decr_prefix("git", "git commit"));
+    assert_eq!(c.get().as_deref(), Some("git push"));
+    assert!(c.decr_prefix("git", "git push"));
+    assert_eq!(c.get(), None);
+}
+
+#[test]
+fn share_between_histories() {
+    use std::env;
+    use std::fs;
+
+    let path = env::temp_dir().join(format!("copperline-shared-{}", ::std::process::id()));
+    let _ = fs::remove_file(&path);
+    let mut a = History::new();
+    let mut b = History::new();
+    a.push("before".to_string());
+    a.share(&path).unwrap();
+    b.share(&path).unwrap();
+    assert_eq!(b.get(0).as_deref(), Some("before"));
+
+    a.push("from a".to_string());
+    b.push("from b".to_string());
+    a.sync().unwrap();
+    b.sync().unwrap();
+    a.sync().unwrap();
+    // nothing new on either side
+    b.sync().unwrap();
+
+    let mut loaded = History::new();
+    loaded.load(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(loaded.len(), 3);
+    assert_eq!(loaded.get(0).as_deref(), Some("from b"));
+    assert_eq!(loaded.get(1).as_deref(), Some("from a"));
+    assert_eq!(a.get(0).as_deref(), Some("from b"));
+    assert_eq!(b.get(0).as_deref(), Some("from a"));
+    assert_eq!(a.len(), 3);
+    assert_eq!(b.len(), 3);
+}
+
+#[test]
+fn save_and_append_shared() {
+    use std::env;
+    use std::fs;
+
+    let path = env::temp_dir().join(format!("copperline-shared-append-{}", ::std::process::id()));
+    let _ = fs::remove_file(&path);
+    let mut h = History::new();
+    h.share(&path).unwrap();
+    h.push_entry(HistoryEntry::new("one".to_string()));
+    h.append(&path).unwrap();
+    h.sync().unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "one\n");
+    assert_eq!(h.len(), 1);
+
+    h.push_entry(HistoryEntry::new("two".to_string()));
+    h.save(&path).unwrap();
+    h.sync().unwrap();
+    let contents = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(contents, "one\ntwo\n");
+    assert_eq!(h.len(), 2);
+    assert_eq!(h.get(0).as_deref(), Some("two"));
+}
+
+#[test]
+fn share_with_incomplete_line() {
+    use std::env;
+    use std::fs;
+
+    let path = env::temp_dir().join(format!("copperline-partial-{}", ::std::process::id()));
+    fs::write(&path, "one\nfo").unwrap();
+    let mut h = History::new();
+    h.push_entry(HistoryEntry::new("bar".to_string()));
+    h.share(&path).unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "one\nfo");
+    assert_eq!(h.len(), 2);
+
+    // the other writer finishes its line
+    OpenOptions::new().append(true).open(&path).unwrap().write_all(b"o\n").unwrap();
+    h.sync().unwrap();
+    let contents = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(contents, "one\nfoo\nbar\n");
+    assert_eq!(h.get(0).as_deref(), Some("foo"));
+    assert_eq!(h.get(1).as_deref(), Some("bar"));
+    assert_eq!(h.len(), 3);
+}
+
+#[test]
+fn glob_patterns() {
+    assert!(glob_match("ls", "ls"));
+    assert!(!glob_match("ls", "ls -l"));
+    assert!(glob_match("ls*", "ls -l"));
+    assert!(glob_match("*", ""));
+    assert!(glob_match("?d *", "cd /tmp"));
+    assert!(glob_match("*a*b", "xaxxab"));
+    assert!(!glob_match("*a*b", "xaxxa"));
+    assert!(!glob_match("?", ""));
+}
+
+#[test]
+fn policy_duplicates() {
+    let lines = ["a", "b", "a", "a", "c", "b"];
+    let collect = |duplicates: HistoryDuplicates| {
+        let mut h = History::new();
+        h.set_policy(HistoryPolicy { duplicates: duplicates, ..HistoryPolicy::default() });
+        for line in lines.iter() {
+            h.push(line.to_string());
+        }
+        (0..h.len()).map(|i| h.get(i).unwrap().into_owned()).collect::<Vec<_>>()
+    };
+    assert_eq!(collect(HistoryDuplicates::IgnoreConsecutive), vec!["b", "c", "a", "b", "a"]);
+    assert_eq!(collect(HistoryDuplicates::IgnoreAll), vec!["c", "b", "a"]);
+    assert_eq!(collect(HistoryDuplicates::EraseOlder), vec!["b", "c", "a"]);
+}
+
+#[test]
+fn policy_ignore() {
+    let mut h = History::new();
+    h.set_policy(HistoryPolicy {
+        ignore_space: true,
+        ignore_patterns: vec!["ls".to_string(), "rm *".to_string()],
+        ..HistoryPolicy::default()
+    });
+    h.push(" secret".to_string());
+    h.push("ls".to_string());
+    h.push("rm -rf /tmp/x".to_string());
+    h.push("ls -l".to_string());
+    assert_eq!(h.len(), 1);
+    assert_eq!(h.get(0).as_deref(), Some("ls -l"));
+}
+
+#[test]
+fn policy_limits() {
+    let mut h = History::new();
+    for line in ["one", "two", "three", "four"].iter() {
+        h.push(line.to_string());
+    }
+    h.set_policy(HistoryPolicy { max_len: Some(3), ..HistoryPolicy::default() });
+    assert_eq!(h.len(), 3);
+    assert_eq!(h.get(2).as_deref(), Some("two"));
+    h.set_policy(HistoryPolicy { max_bytes: Some(9), ..HistoryPolicy::default() });
+    assert_eq!(h.len(), 2);
+    h.push("five".to_string());
+    assert_eq!(h.len(), 2);
+    assert_eq!(h.get(0).as_deref(), Some("five"));
+    assert_eq!(h.get(1).as_deref(), Some("four"));
+}
+
+#[test]
+fn escape_roundtrip() {
+    let entries = ["plain", "two\nlines", "back\\slash\\n", "crlf\r\n", "tab\t", "ünïcödé 䨻", "#hash"];
+    for entry in entries.iter() {
+        let escaped = escape_entry(entry);
+        assert!(!escaped.contains('\n'));
+        assert!(!escaped.starts_with('#'));
+        assert_eq!(unescape(&escaped), Some(entry.to_string()));
+    }
+}
+
+#[test]
+fn unescape_invalid() {
+    assert_eq!(unescape("trailing\\"), None);
+    assert_eq!(unescape("unknown\\q"), None);
+}
+
+#[test]
+fn parse_entries_reports_line() {
+    assert_eq!(parse_entries(b"one\ntwo\\\n"), Err(Error::InvalidHistory(2)));
+    assert_eq!(parse_entries(b"one\n\xff\n"), Err(Error::InvalidHistory(2)));
+    assert_eq!(parse_entries(b"#time=1\n#time=2\none\n"), Err(Error::InvalidHistory(2)));
+    assert_eq!(parse_entries(b"one\n#time=1\n"), Err(Error::InvalidHistory(2)));
+    assert_eq!(parse_entries(b"#time=x\none\n"), Err(Error::InvalidHistory(1)));
+    assert_eq!(parse_entries(b"#nokey\none\n"), Err(Error::InvalidHistory(1)));
+    let lines = parse_entries(b"one\n\n#unknown=1\ntwo\n").unwrap()
+        .into_iter().map(|e| e.line).collect::<Vec<_>>();
+    assert_eq!(lines, vec!["one".to_string(), "".to_string(), "two".to_string()]);
+}
+
+#[test]
+fn metadata_roundtrip() {
+    let mut entry = HistoryEntry::new("#!/bin/sh\tx".to_string());
+    entry.timestamp = Some(UNIX_EPOCH + Duration::from_millis(1500000000123));
+    entry.duration = Some(Duration::from_millis(2500));
+    entry.exit_status = Some(-1);
+    entry.cwd = Some(PathBuf::from("/home/a=b/c\td"));
+    entry.session = Some("tty1".to_string());
+    entry.tags.insert("k=ey".to_string(), "va\\lue".to_string());
+    entry.tags.insert("host".to_string(), "".to_string());
+    let plain = HistoryEntry::new("plain".to_string());
+
+    let bytes = format_entries(vec![&plain, &entry].into_iter());
+    assert_eq!(parse_entries(&bytes), Ok(vec![entry, plain]));
+}
+
+#[test]
+fn save_load_append() {
+    use std::env;
+    use std::fs;
+
+    let path = env::temp_dir().join(format!("copperline-history-{}", ::std::process::id()));
+    let mut h = History::new();
+    h.push("first".to_string());
+    h.push("multi\nline".to_string());
+    h.save(&path).unwrap();
+    h.push("ärger".to_string());
+    h.append(&path).unwrap();
+    // nothing new to append
+    h.append(&path).unwrap();
+
+    let mut loaded = History::new();
+    loaded.load(&path).unwrap();
+    let mut limited = History::new();
+    limited.set_policy(HistoryPolicy { max_len: Some(1), ..HistoryPolicy::default() });
+    limited.load(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(limited.len(), 1);
+    assert_eq!(limited.get(0).as_deref(), Some("ärger"));
+    assert_eq!(loaded.len(), 3);
+    assert_eq!(loaded.get(0).as_deref(), Some("ärger"));
+    assert_eq!(loaded.get(1).as_deref(), Some("multi\nline"));
+    assert_eq!(loaded.get(2).as_deref(), Some("first"));
+}
+
+#[test]
+fn load_keeps_unsaved() {
+    use std::env;
+    use std::fs;
+
+    let saved = env::temp_dir().join(format!("copperline-saved-{}", ::std::process::id()));
+    let appended = env::temp_dir().join(format!("copperline-appended-{}", ::std::process::id()));
+    let _ = fs::remove_file(&appended);
+    let mut other = History::new();
+    other.push("saved".to_string());
+    other.save(&saved).unwrap();
+
+    let mut h = History::new();
+    h.push("before".to_string());
+    h.load(&saved).unwrap();
+    h.push("after".to_string());
+    h.append(&appended).unwrap();
 
+    let mut loaded = History::new();
+    loaded.load(&appended).unwrap();
+    fs::remove_file(&saved).unwrap();
+    fs::remove_file(&appended).unwrap();
+    assert_eq!(loaded.len(), 2);
+    assert_eq!(loaded.get(0).as_deref(), Some("after"));
+    assert_eq!(loaded.get(1).as_deref(), Some("before"));
+    assert_eq!(h.get(2).as_deref(), Some("saved"));
 }
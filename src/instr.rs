@@ -1,6 +1,7 @@
 use parser;
 use edit::ModeState;
 use edit::ViMode;
+use history::SearchDirection;
 
 pub enum CommonInstr {
     Done,
@@ -11,7 +12,55 @@ pub enum CommonInstr {
 
 pub enum HistoryInstr {
     Next,
-    Prev
+    Prev,
+    First,
+    Last
+}
+
+pub enum SearchInstr {
+    Start(SearchDirection),
+    Insert(String),
+    DeleteChar,
+    Accept,
+    Abort
+}
+
+pub enum ViSearchInstr {
+    Start(SearchDirection),
+    Insert(String),
+    DeleteChar,
+    Execute,
+    Abort,
+    Repeat,
+    RepeatOpposite
+}
+
+pub enum ListingInstr {
+    Yes,
+    No,
+    NextPage,
+    NextLine
+}
+
+pub enum MenuInstr {
+    Next,
+    Prev,
+    Up,
+    Down,
+    Left,
+    Right,
+    Accept,
+    Abort
+}
+
+pub enum PickerInstr {
+    Start,
+    Insert(String),
+    DeleteChar,
+    Up,
+    Down,
+    Accept,
+    Abort
 }
 
 pub enum MoveCursorInstr {
@@ -24,6 +73,11 @@ pub enum MoveCursorInstr {
 pub enum Instr {
     Common(CommonInstr),
     History(HistoryInstr),
+    Search(SearchInstr),
+    Picker(PickerInstr),
+    ViSearch(ViSearchInstr),
+    Listing(ListingInstr),
+    Menu(MenuInstr),
     MoveCursor(MoveCursorInstr),
     MoveEndOfWordRight,
     MoveEndOfWordWsRight,
@@ -31,6 +85,10 @@ pub enum Instr {
     MoveWordWsRight,
     MoveWordLeft,
     MoveWordWsLeft,
+    MoveWordForward,
+    MoveToMatchingBracket,
+    MoveOverBracketsForward,
+    MoveOverBracketsBackward,
     MoveCharRight(char),
     MoveCharLeft(char),
     MoveBeforeCharRight(char),
@@ -55,7 +113,12 @@ pub enum Instr {
     DeleteMode,
     ChangeMode,
     Digit(u32),
-    DoneOrEof
+    DoneOrEof,
+    ExpandHistory,
+    Argument(u32),
+    YankLastArg,
+    YankNthArg,
+    Complete
 }
 
 #[derive(Copy,Clone,PartialEq)]
@@ -103,6 +166,116 @@ fn emacs_mode(token: parser::Token) -> Instr {
         parser::Token::CtrlJ        => Instr::Common(CommonInstr::Done),
         parser::Token::CtrlC        => Instr::Common(CommonInstr::Cancel),
         parser::Token::CtrlL        => Instr::Common(CommonInstr::Clear),
+        parser::Token::CtrlR        => Instr::Search(SearchInstr::Start(SearchDirection::Reverse)),
+        parser::Token::CtrlS        => Instr::Search(SearchInstr::Start(SearchDirection::Forward)),
+        parser::Token::Tab          => Instr::Complete,
+        parser::Token::EscBracketZ  => Instr::Menu(MenuInstr::Prev),
+        parser::Token::Meta('^')    => Instr::ExpandHistory,
+        parser::Token::Meta('r')    => Instr::Picker(PickerInstr::Start),
+        parser::Token::Meta('f')    => Instr::MoveWordForward,
+        parser::Token::Meta('\r')   => Instr::InsertAtCursor("\n".to_owned()),
+        parser::Token::Meta('\x06') => Instr::MoveOverBracketsForward,
+        parser::Token::Meta('\x02') => Instr::MoveOverBracketsBackward,
+        parser::Token::Meta('<')    => Instr::History(HistoryInstr::First),
+        parser::Token::Meta('>')    => Instr::History(HistoryInstr::Last),
+        parser::Token::Meta('.')    => Instr::YankLastArg,
+        parser::Token::Meta('_')    => Instr::YankLastArg,
+        parser::Token::Meta('\x19') => Instr::YankNthArg,
+        parser::Token::Meta(c) if c.is_ascii_digit() => Instr::Argument(c as u32 - '0' as u32),
+        _                           => Instr::Common(CommonInstr::Noop)
+    }
+}
+
+/// Interprets a token while an incremental history search is active.
+///
+/// Returns `None` for tokens that end the search, which are then
+/// interpreted by the regular edit mode.
+pub fn search_mode(token: &parser::Token) -> Option<Instr> {
+    match *token {
+        parser::Token::Text(ref text) => Some(Instr::Search(SearchInstr::Insert(text.clone()))),
+        parser::Token::Backspace    => Some(Instr::Search(SearchInstr::DeleteChar)),
+        parser::Token::CtrlH        => Some(Instr::Search(SearchInstr::DeleteChar)),
+        parser::Token::CtrlR        => Some(Instr::Search(SearchInstr::Start(SearchDirection::Reverse))),
+        parser::Token::CtrlS        => Some(Instr::Search(SearchInstr::Start(SearchDirection::Forward))),
+        parser::Token::Esc          => Some(Instr::Search(SearchInstr::Accept)),
+        parser::Token::CtrlG        => Some(Instr::Search(SearchInstr::Abort)),
+        _                           => None
+    }
+}
+
+/// Interprets a token while completions are listed, either asking whether
+/// to list them at all or waiting at `--More--`.
+pub fn listing_mode(token: parser::Token, asking: bool) -> Instr {
+    match token {
+        parser::Token::Text(ref text) => match (text.as_ref(), asking) {
+            ("y", _) | ("Y", _)     => Instr::Listing(ListingInstr::Yes),
+            (" ", true)             => Instr::Listing(ListingInstr::Yes),
+            (" ", false)            => Instr::Listing(ListingInstr::NextPage),
+            ("n", _) | ("N", _)     => Instr::Listing(ListingInstr::No),
+            ("q", _) | ("Q", _)     => Instr::Listing(ListingInstr::No),
+            _                       => Instr::Common(CommonInstr::Noop)
+        },
+        parser::Token::Enter if !asking => Instr::Listing(ListingInstr::NextLine),
+        parser::Token::CtrlJ if !asking => Instr::Listing(ListingInstr::NextLine),
+        parser::Token::Backspace    => Instr::Listing(ListingInstr::No),
+        parser::Token::Esc          => Instr::Listing(ListingInstr::No),
+        parser::Token::CtrlC        => Instr::Listing(ListingInstr::No),
+        parser::Token::CtrlG        => Instr::Listing(ListingInstr::No),
+        _                           => Instr::Common(CommonInstr::Noop)
+    }
+}
+
+/// Interprets a token while cycling through completions.
+///
+/// Returns `None` for tokens that keep the current candidate and end
+/// the menu, which are then interpreted by the regular edit mode.
+pub fn menu_mode(token: &parser::Token) -> Option<Instr> {
+    match *token {
+        parser::Token::Tab          => Some(Instr::Menu(MenuInstr::Next)),
+        parser::Token::CtrlN        => Some(Instr::Menu(MenuInstr::Next)),
+        parser::Token::EscBracketZ  => Some(Instr::Menu(MenuInstr::Prev)),
+        parser::Token::CtrlP        => Some(Instr::Menu(MenuInstr::Prev)),
+        parser::Token::EscBracketA  => Some(Instr::Menu(MenuInstr::Up)),
+        parser::Token::EscBracketB  => Some(Instr::Menu(MenuInstr::Down)),
+        parser::Token::EscBracketC  => Some(Instr::Menu(MenuInstr::Right)),
+        parser::Token::EscBracketD  => Some(Instr::Menu(MenuInstr::Left)),
+        parser::Token::Enter        => Some(Instr::Menu(MenuInstr::Accept)),
+        parser::Token::CtrlJ        => Some(Instr::Menu(MenuInstr::Accept)),
+        parser::Token::CtrlG        => Some(Instr::Menu(MenuInstr::Abort)),
+        _                           => None
+    }
+}
+
+/// Interprets a token while the fuzzy history finder is open.
+pub fn picker_mode(token: parser::Token) -> Instr {
+    match token {
+        parser::Token::Text(text)   => Instr::Picker(PickerInstr::Insert(text)),
+        parser::Token::Backspace    => Instr::Picker(PickerInstr::DeleteChar),
+        parser::Token::CtrlH        => Instr::Picker(PickerInstr::DeleteChar),
+        parser::Token::EscBracketA  => Instr::Picker(PickerInstr::Up),
+        parser::Token::CtrlP        => Instr::Picker(PickerInstr::Up),
+        parser::Token::EscBracketB  => Instr::Picker(PickerInstr::Down),
+        parser::Token::CtrlN        => Instr::Picker(PickerInstr::Down),
+        parser::Token::Enter        => Instr::Picker(PickerInstr::Accept),
+        parser::Token::CtrlJ        => Instr::Picker(PickerInstr::Accept),
+        parser::Token::Esc          => Instr::Picker(PickerInstr::Abort),
+        parser::Token::CtrlG        => Instr::Picker(PickerInstr::Abort),
+        parser::Token::CtrlC        => Instr::Picker(PickerInstr::Abort),
+        _                           => Instr::Common(CommonInstr::Noop)
+    }
+}
+
+/// Interprets a token while the query of a vi history search is typed.
+pub fn vi_search_mode(token: parser::Token) -> Instr {
+    match token {
+        parser::Token::Text(text)   => Instr::ViSearch(ViSearchInstr::Insert(text)),
+        parser::Token::Backspace    => Instr::ViSearch(ViSearchInstr::DeleteChar),
+        parser::Token::CtrlH        => Instr::ViSearch(ViSearchInstr::DeleteChar),
+        parser::Token::Enter        => Instr::ViSearch(ViSearchInstr::Execute),
+        parser::Token::CtrlJ        => Instr::ViSearch(ViSearchInstr::Execute),
+        parser::Token::Esc          => Instr::ViSearch(ViSearchInstr::Abort),
+        parser::Token::CtrlC        => Instr::ViSearch(ViSearchInstr::Abort),
+        parser::Token::CtrlG        => Instr::ViSearch(ViSearchInstr::Abort),
         _                           => Instr::Common(CommonInstr::Noop)
     }
 }
@@ -134,6 +307,13 @@ fn vi_insert_mode(token: parser::Token) -> Instr {
     match token {
         parser::Token::Text(text)   => Instr::InsertAtCursor(text),
         parser::Token::CtrlH        => Instr::DeleteCharLeftOfCursor,
+        parser::Token::CtrlR        => Instr::Search(SearchInstr::Start(SearchDirection::Reverse)),
+        parser::Token::CtrlS        => Instr::Search(SearchInstr::Start(SearchDirection::Forward)),
+        parser::Token::Meta('\r')   => Instr::InsertAtCursor("\n".to_owned()),
+        parser::Token::Tab          => Instr::Complete,
+        parser::Token::EscBracketZ  => Instr::Menu(MenuInstr::Prev),
+        parser::Token::CtrlN        => Instr::Menu(MenuInstr::Next),
+        parser::Token::CtrlP        => Instr::Menu(MenuInstr::Prev),
         _                           => vi_common(&token),
     }
 }
@@ -146,6 +326,12 @@ fn vi_normal_mode(token: parser::Token) -> Instr {
             "l"                     => Instr::MoveCursor(MoveCursorInstr::Right),
             "0"                     => Instr::Digit(0),
             "$"                     => Instr::MoveCursor(MoveCursorInstr::End),
+            "%"                     => Instr::MoveToMatchingBracket,
+
+            "/"                     => Instr::ViSearch(ViSearchInstr::Start(SearchDirection::Reverse)),
+            "?"                     => Instr::ViSearch(ViSearchInstr::Start(SearchDirection::Forward)),
+            "n"                     => Instr::ViSearch(ViSearchInstr::Repeat),
+            "N"                     => Instr::ViSearch(ViSearchInstr::RepeatOpposite),
 
             "x"                     => Instr::DeleteCharRightOfCursor,
             "s"                     => Instr::Substitute,
@@ -211,6 +397,7 @@ fn vi_change_delete_common(token: &parser::Token) -> Instr {
             "l"                     => Instr::MoveCursor(MoveCursorInstr::Right),
             "0"                     => Instr::Digit(0),
             "$"                     => Instr::MoveCursor(MoveCursorInstr::End),
+            "%"                     => Instr::MoveToMatchingBracket,
 
             "e"                     => Instr::MoveEndOfWordRight,
             "E"                     => Instr::MoveEndOfWordWsRight,
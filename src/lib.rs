@@ -8,7 +8,21 @@
 //!
 //! - Cursor movement and text insertion
 //! - Jumps (via `C-a` and `C-e`)
-//! - History browsing (via `C-p` and `C-n`)
+//! - History browsing (via `C-p` and `C-n`, `M-<` and `M->`)
+//! - Inserting words of previous lines (via `M-.` and `M-C-y`)
+//! - Completion (via `Tab`) of file names or with a custom `Completer`,
+//!   listing candidates with descriptions under group headings
+//! - Syntax highlighting with a custom `Highlighter`
+//! - Matching bracket highlighting and jumps (via `C-M-f`, `C-M-b` and `%`)
+//! - A right-aligned prompt, hidden when the line runs into it
+//! - Input spanning several lines, continued while a `Validator` finds
+//!   it incomplete or with `M-Enter`, moving between them with `Up` and
+//!   `Down` before browsing history
+//! - Fish-style autosuggestions, accepted with `Right` or `C-e`
+//! - Incremental history search (via `C-r` and `C-s`)
+//! - Fuzzy history search (via `M-r`)
+//! - Persistent history files, optionally shared between processes
+//! - Bash-style history expansion (`!!`, `!$`, `^old^new`, ...)
 //!
 //! It has a clean, hackable codebase, which I hope will foster
 //! contributions so that the Rust ecosystem will soon be able to utilise
@@ -25,38 +39,102 @@ mod error;
 mod builder;
 mod buffer;
 mod history;
+mod expand;
+mod fuzzy;
+mod complete;
+mod hint;
+mod highlight;
+mod prompt;
+mod validate;
+mod style;
 mod parser;
 mod instr;
 mod edit;
 mod run;
 mod term;
 
+use std::borrow::Cow;
 use std::mem::drop;
+use std::path::Path;
+use std::time::Duration;
 use std::os::unix::io::{RawFd, AsRawFd};
 
 pub use enc::Encoding;
 pub use error::Error;
 use history::History;
+pub use history::{HistoryDuplicates, HistoryEntry, HistoryPolicy};
+pub use history::{HistoryStore, MemoryHistory, SearchDirection};
 use term::Term;
 use edit::EditCtx;
 pub use edit::EditMode;
+pub use complete::{Candidate, Completer, Completion, FilenameCompleter};
+pub use hint::{Hinter, HistoryHinter};
+pub use highlight::{Highlighter, Span};
+pub use style::{Color, Style};
+pub use validate::{ValidationResult, Validator};
 use run::RunIO;
 
 pub struct Copperline {
     term: Term,
-    history: History
+    history: History,
+    completer: Option<Box<dyn Completer>>,
+    hinter: Option<Box<dyn Hinter>>,
+    highlighter: Option<Box<dyn Highlighter>>,
+    validator: Option<Box<dyn Validator>>,
+    // the error of the last sync with the shared history file
+    sync_error: Option<Error>
 }
 
 pub struct Config {
     pub encoding: Encoding,
-    pub mode: EditMode
+    pub mode: EditMode,
+    /// When the line is not empty, only browse history entries that start
+    /// with the text before the cursor.
+    pub history_prefix_search: bool,
+    /// Expand bash-style history references like `!!` or `!$` when a line
+    /// is accepted. `M-^` expands them in place.
+    pub history_expansion: bool,
+    /// The number of matches the fuzzy history finder (`M-r`) shows at once.
+    pub fuzzy_search_rows: usize,
+    /// Ask before listing at least this many completions.
+    pub completion_query_items: usize,
+    /// Replace the completed text with each candidate in turn on `Tab`,
+    /// rather than completing their common prefix and listing them.
+    /// `S-Tab` always cycles backwards.
+    pub menu_complete: bool,
+    /// Suggest the rest of the most recent history entry starting with the
+    /// line, unless a custom `Hinter` is set. `Right` or `C-e` at the end
+    /// of the line accepts the suggestion and `M-f` accepts a word of it.
+    pub autosuggestions: bool,
+    /// Highlight the bracket matching the one at the cursor, skipping
+    /// brackets within quotes. `%` in vi jumps to it, while `C-M-f` and
+    /// `C-M-b` move forward and back over brackets.
+    pub match_brackets: bool,
+    /// A prompt drawn against the right edge of the terminal, hidden while
+    /// the line would run into it. It may be styled like the prompt.
+    pub right_prompt: Option<String>,
+    /// Erase the right prompt when the line is done.
+    pub transient_right_prompt: bool,
+    /// The prompt of the lines after the first, started when the validator
+    /// finds the line incomplete.
+    pub continuation_prompt: String
 }
 
 impl Config {
     pub fn default() -> Config {
         Config {
             encoding: Encoding::Utf8,
-            mode: EditMode::Emacs
+            mode: EditMode::Emacs,
+            history_prefix_search: false,
+            history_expansion: false,
+            fuzzy_search_rows: 10,
+            completion_query_items: 100,
+            menu_complete: false,
+            autosuggestions: false,
+            match_brackets: true,
+            right_prompt: None,
+            transient_right_prompt: false,
+            continuation_prompt: "> ".to_owned()
         }
     }
 }
@@ -77,18 +155,43 @@ impl Copperline {
     pub fn new_from_raw_fds(ifd: RawFd, ofd: RawFd) -> Copperline {
         Copperline {
             term: Term::new(ifd, ofd),
-            history: History::new()
+            history: History::new(),
+            completer: None,
+            hinter: None,
+            highlighter: None,
+            validator: None,
+            sync_error: None
         }
     }
 
     /// Reads a line from the input using the specified prompt and config.
+    ///
+    /// The prompt may be coloured with escape sequences. Other text that
+    /// takes no space on the terminal goes between `\x01` and `\x02`.
+    /// A prompt may have several lines, of which only the last one is
+    /// drawn again while editing.
     pub fn read_line(&mut self, prompt: &str, cfg: &Config) -> Result<String, Error> {
-        let enc = enc::to_encoding_ref(&cfg.encoding);
         if Term::is_unsupported_term() || !self.term.is_a_tty() {
             return Err(Error::UnsupportedTerm);
         }
+        // a broken history file does not keep the line from being edited
+        self.sync_error = self.history.sync().err();
         let mut io = try!(self.term.acquire_io());
-        let ctx = EditCtx::new(prompt, &self.history, enc, cfg.mode);
+        let mut ctx = EditCtx::new(prompt, self.history.store(), cfg);
+        if let Some(ref completer) = self.completer {
+            ctx.set_completer(&**completer);
+        }
+        match self.hinter {
+            Some(ref hinter) => ctx.set_hinter(&**hinter),
+            None if cfg.autosuggestions => ctx.set_hinter(&HistoryHinter),
+            None => {}
+        }
+        if let Some(ref highlighter) = self.highlighter {
+            ctx.set_highlighter(&**highlighter);
+        }
+        if let Some(ref validator) = self.validator {
+            ctx.set_validator(&**validator);
+        }
         let res = run::run(ctx, &mut io);
         drop(io);
         println!("");
@@ -100,24 +203,82 @@ impl Copperline {
         self.read_line(prompt, &Config::default())
     }
 
+    /// Sets the completer asked for candidates when `Tab` is pressed.
+    pub fn set_completer(&mut self, completer: Box<dyn Completer>) {
+        self.completer = Some(completer)
+    }
+
+    /// Sets the hinter suggesting how to continue the line.
+    pub fn set_hinter(&mut self, hinter: Box<dyn Hinter>) {
+        self.hinter = Some(hinter)
+    }
+
+    /// Sets the highlighter colouring the line as it is edited.
+    pub fn set_highlighter(&mut self, highlighter: Box<dyn Highlighter>) {
+        self.highlighter = Some(highlighter)
+    }
+
+    /// Sets the validator deciding whether `Enter` accepts the line.
+    pub fn set_validator(&mut self, validator: Box<dyn Validator>) {
+        self.validator = Some(validator)
+    }
+
     /// Returns the current length of the history.
     pub fn get_current_history_length(&self) -> usize {
         self.history.len()
     }
 
-    /// Adds a line to the history.
+    /// Adds a line to the history, recording the current time, working
+    /// directory and session.
     pub fn add_history(&mut self, line: String) {
         self.history.push(line)
     }
 
+    /// Adds an entry to the history, keeping its metadata as is.
+    pub fn add_history_entry(&mut self, entry: HistoryEntry) {
+        self.history.push_entry(entry)
+    }
+
     /// Retrieves a line from the history by index.
-    pub fn get_history_item(&self, idx: usize) -> Option<&String> {
+    pub fn get_history_item(&self, idx: usize) -> Option<Cow<'_, str>> {
         self.history.get(idx)
     }
 
+    /// Retrieves an entry from the history by index.
+    pub fn get_history_entry(&self, idx: usize) -> Option<Cow<'_, HistoryEntry>> {
+        self.history.get_entry(idx)
+    }
+
+    /// Records the exit status of running the line of a history entry,
+    /// e.g. the most recent one. Returns false if there is no such entry or
+    /// the history store does not support modifying entries.
+    pub fn set_history_exit_status(&mut self, idx: usize, status: i32) -> bool {
+        self.history.set_exit_status(idx, status)
+    }
+
+    /// Records how long running the line of a history entry took.
+    pub fn set_history_duration(&mut self, idx: usize, duration: Duration) -> bool {
+        self.history.set_duration(idx, duration)
+    }
+
+    /// Sets a tag of a history entry, replacing its previous value.
+    pub fn set_history_tag(&mut self, idx: usize, key: String, value: String) -> bool {
+        self.history.set_tag(idx, key, value)
+    }
+
+    /// Iterates over the entries of the history, most recent first.
+    pub fn history_entries<'a>(&'a self) -> Box<dyn Iterator<Item=Cow<'a, HistoryEntry>> + 'a> {
+        self.history.entries()
+    }
+
+    /// Sets the session recorded with lines added through `add_history`.
+    pub fn set_history_session(&mut self, session: Option<String>) {
+        self.history.set_session(session)
+    }
+
     /// Removes an item from the history by index and returns it.
     pub fn remove_history_item(&mut self, idx: usize) -> Option<String> {
-        self.history.remove(idx)
+        self.history.remove(idx).map(|e| e.line)
     }
 
     /// Clears the current history.
@@ -125,6 +286,58 @@ impl Copperline {
         self.history.clear()
     }
 
+    /// Replaces the store that keeps the history, which is a `MemoryHistory` by default.
+    ///
+    /// The entries of the previous store are dropped, while those already in
+    /// the new store are kept, subject to the limits of the history policy.
+    pub fn set_history_store(&mut self, store: Box<dyn HistoryStore>) {
+        self.history.set_store(store)
+    }
+
+    /// Sets the limits and filters for lines added to the history.
+    ///
+    /// Entries exceeding the new limits are evicted right away.
+    pub fn set_history_policy(&mut self, policy: HistoryPolicy) {
+        self.history.set_policy(policy)
+    }
+
+    /// Loads the history from a file, adding its entries to the current history.
+    pub fn load_history<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
+        self.history.load(path)
+    }
+
+    /// Saves the current history to a file, replacing its contents.
+    pub fn save_history<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
+        self.history.save(path)
+    }
+
+    /// Appends the lines added since the last load, save or append to a file.
+    pub fn append_history<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
+        self.history.append(path)
+    }
+
+    /// Shares the history with other processes using the same file.
+    ///
+    /// The lines in the file are added to the history right away. Afterwards,
+    /// every call to `read_line` appends the lines added since the previous
+    /// call and picks up the lines other processes appended in the meantime.
+    /// Errors doing so are kept for `take_sync_error`.
+    pub fn share_history<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
+        self.history.share(path)
+    }
+
+    /// Exchanges lines with the shared history file without waiting for the
+    /// next call to `read_line`, e.g. before exiting.
+    pub fn sync_history(&mut self) -> Result<(), Error> {
+        self.history.sync()
+    }
+
+    /// Returns the error of the last exchange with the shared history file
+    /// made by `read_line`, if it failed.
+    pub fn take_sync_error(&mut self) -> Option<Error> {
+        self.sync_error.take()
+    }
+
     /// Clears the screen.
     pub fn clear_screen(&mut self) -> Result<(), Error> {
         let mut io = try!(self.term.acquire_io());
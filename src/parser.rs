@@ -40,6 +40,8 @@ pub enum Token {
     EscBracketD,
     EscBracketH,
     EscBracketF,
+    EscBracketZ,
+    Meta(char),
     Text(String)
 }
 
@@ -134,6 +136,7 @@ fn parse_esc_bracket(vec: &[u8]) -> ParseResult<Token> {
             'D' => Ok(ParseSuccess(Token::EscBracketD, 3)),
             'F' => Ok(ParseSuccess(Token::EscBracketF, 3)),
             'H' => Ok(ParseSuccess(Token::EscBracketH, 3)),
+            'Z' => Ok(ParseSuccess(Token::EscBracketZ, 3)),
             _ => Err(ParseError::Error(2)) // TODO: implement more
         }
     }
@@ -143,8 +146,13 @@ fn parse_esc(vec: &[u8]) -> ParseResult<Token> {
     let c = try!(parse_char(vec, 1)).0 as char;
     if c == '[' {
         parse_esc_bracket(vec)
-    } else if c == '0' {
+    } else if c == 'O' {
         Err(ParseError::Error(2)) // TODO: implement
+    } else if (' '..='~').contains(&c) {
+        Ok(ParseSuccess(Token::Meta(c), 2))
+    } else if c < ' ' && c != '\x1b' {
+        /* Meta with a control character, like M-C-y. */
+        Ok(ParseSuccess(Token::Meta(c), 2))
     } else {
         Err(ParseError::Error(2))
     }
@@ -203,6 +211,16 @@ pub fn parse_cursor_pos(vec: &[u8]) -> ParseResult<(u64, u64)> {
     Ok(ParseSuccess((x, y), 4+n+m))
 }
 
+#[test]
+fn parse_meta() {
+    use encoding::all::ASCII;
+    assert_eq!(parse(b"\x1b^", ASCII), Ok(ParseSuccess(Token::Meta('^'), 2)));
+    assert_eq!(parse(b"\x1b[A", ASCII), Ok(ParseSuccess(Token::EscBracketA, 3)));
+    assert_eq!(parse(b"\x1b", ASCII), Ok(ParseSuccess(Token::Esc, 1)));
+    assert_eq!(parse(b"\x1b\x19", ASCII), Ok(ParseSuccess(Token::Meta('\x19'), 2)));
+    assert_eq!(parse(b"\x1bO", ASCII), Err(ParseError::Error(2)));
+}
+
 #[test]
 fn parse_cursor_pos_full() {
     let v = vec![27, 91, 48 + 4, 48 + 2, 59, 48 + 6, 82];
@@ -0,0 +1,137 @@
+use unicode_width::UnicodeWidthChar;
+
+/// Marks the start of a region of the prompt that takes no space, like in
+/// readline.
+const START_INVISIBLE: char = '\x01';
+/// Marks the end of an invisible region.
+const END_INVISIBLE: char = '\x02';
+
+/// A prompt prepared for drawing. Only the last line of a prompt with
+/// several lines is drawn again while the line is edited.
+pub struct Prompt {
+    /// The lines before the last one, each ending with `\r\n`.
+    pub above: String,
+    /// The last line without the markers around invisible regions.
+    pub text: String,
+    /// The number of columns the last line takes up.
+    pub width: usize,
+    /// The prompt drawn against the right edge, or an empty string.
+    pub right: String,
+    pub right_width: usize,
+    /// The prompt of lines continuing the first one.
+    pub continuation: String,
+    pub continuation_width: usize
+}
+
+impl Prompt {
+    pub fn new(prompt: &str, right: Option<&str>, continuation: &str) -> Prompt {
+        let right = right.unwrap_or("");
+        let (above, prompt) = match prompt.rfind('\n') {
+            Some(i) => (&prompt[..i + 1], &prompt[i + 1..]),
+            None => ("", prompt)
+        };
+        Prompt {
+            above: strip_markers(above).replace("\r\n", "\n").replace('\n', "\r\n"),
+            text: strip_markers(prompt),
+            width: display_width(prompt),
+            right: strip_markers(right),
+            right_width: display_width(right),
+            continuation: strip_markers(continuation),
+            continuation_width: display_width(continuation)
+        }
+    }
+
+    /// Returns the column the right prompt starts at, unless it would not
+    /// leave a space after the first `used` columns of a line of the width.
+    /// The last column is kept free, so the terminal does not wrap.
+    pub fn right_column(&self, used: usize, width: usize) -> Option<usize> {
+        if self.right_width == 0 || used + 1 + self.right_width >= width {
+            None
+        } else {
+            Some(width - 1 - self.right_width)
+        }
+    }
+}
+
+fn strip_markers(prompt: &str) -> String {
+    prompt.replace([START_INVISIBLE, END_INVISIBLE], "")
+}
+
+/// Returns the number of columns text takes up, leaving out escape
+/// sequences, control characters and regions between `\x01` and `\x02`.
+pub fn display_width(text: &str) -> usize {
+    let mut width = 0;
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            START_INVISIBLE => {
+                for c in chars.by_ref() {
+                    if c == END_INVISIBLE {
+                        break;
+                    }
+                }
+            },
+            '\x1b' => skip_escape(&mut chars),
+            c => width += UnicodeWidthChar::width(c).unwrap_or(0)
+        }
+    }
+    width
+}
+
+/// Skips the rest of an escape sequence after the `ESC`.
+fn skip_escape<I: Iterator<Item=char>>(chars: &mut I) {
+    match chars.next() {
+        // CSI sequences like colours end with a final byte
+        Some('[') => {
+            for c in chars {
+                if ('\x40'..='\x7e').contains(&c) {
+                    break;
+                }
+            }
+        },
+        // OSC sequences like window titles end with BEL or ST
+        Some(']') => {
+            let mut esc = false;
+            for c in chars {
+                if c == '\x07' || (esc && c == '\\') {
+                    break;
+                }
+                esc = c == '\x1b';
+            }
+        },
+        _ => {}
+    }
+}
+
+#[test]
+fn prompt_width() {
+    assert_eq!(display_width("foo> "), 5);
+    assert_eq!(display_width("\x1b[1;32mfoo\x1b[0m> "), 5);
+    assert_eq!(display_width("\x1b]0;title\x07$ "), 2);
+    assert_eq!(display_width("\x1b]0;title\x1b\\$ "), 2);
+    assert_eq!(display_width("日本> "), 6);
+    assert_eq!(display_width("🦀 "), 3);
+    assert_eq!(display_width("\x01\x1b[31m\x02red\x01\x1b[0m\x02 "), 4);
+    let prompt = Prompt::new("\x01\x1b[31m\x02λ\x01\x1b[0m\x02 ", None, "");
+    assert_eq!(prompt.text, "\x1b[31mλ\x1b[0m ");
+    assert_eq!(prompt.width, 2);
+}
+
+#[test]
+fn right_prompt_column() {
+    let prompt = Prompt::new("> ", Some("\x1b[33mmaster\x1b[0m"), "");
+    assert_eq!(prompt.right_width, 6);
+    assert_eq!(prompt.right_column(10, 20), Some(13));
+    assert_eq!(prompt.right_column(12, 20), Some(13));
+    assert_eq!(prompt.right_column(13, 20), None);
+    assert_eq!(Prompt::new("> ", None, "").right_column(0, 20), None);
+}
+
+#[test]
+fn multi_line_prompt() {
+    let prompt = Prompt::new("\x1b[1m~/src\x1b[0m\nstatus\r\n日本> ", None, "");
+    assert_eq!(prompt.above, "\x1b[1m~/src\x1b[0m\r\nstatus\r\n");
+    assert_eq!(prompt.text, "日本> ");
+    assert_eq!(prompt.width, 6);
+    assert_eq!(Prompt::new("> ", None, "").above, "");
+}
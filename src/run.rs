@@ -9,6 +9,11 @@ pub trait RunIO {
     fn read_byte(&mut self) -> Result<u8, Error>;
     fn read_seq(&mut self) -> Result<Vec<u8>, Error>;
 
+    /// Returns the number of columns and rows of the output.
+    fn size(&mut self) -> (usize, usize) {
+        (80, 24)
+    }
+
     fn prompt(&mut self, w: Vec<u8>) -> Result<Vec<u8>, Error> {
         try!(self.write(w));
         self.read_seq()
@@ -51,12 +56,20 @@ fn protect_newline(io: &mut RunIO) -> Result<(), Error> {
 
 fn run_edit<'a>(mut ctx: EditCtx<'a>, io: &mut RunIO) -> Result<String, Error> {
     loop {
+        let (width, height) = io.size();
+        ctx.set_size(width, height);
         match edit(&mut ctx) {
             EditResult::Cont(line) => {
                 let bytes = try!(io.prompt(line));
                 ctx.fill(bytes);
             },
-            EditResult::Halt(res) => { return res; }
+            EditResult::Halt(res) => {
+                let output = ctx.take_output();
+                if !output.is_empty() {
+                    try!(io.write(output));
+                }
+                return res;
+            }
         }
     }
 }
@@ -69,12 +82,14 @@ pub fn run<'a>(ctx: EditCtx<'a>, io: &mut RunIO) -> Result<String, Error> {
 
 #[cfg(test)]
 mod test {
-    use encoding::all::ASCII;
     use super::super::error::Error;
     use super::super::edit::EditCtx;
     use super::super::history::History;
     use super::{RunIO, run_edit};
     use edit::EditMode;
+    use enc::Encoding;
+    use Config;
+    use complete::Completion;
 
     pub struct TestIO {
         input: Vec<u8>,
@@ -104,28 +119,77 @@ mod test {
         }
     }
 
+    /// Returns one token per read, for input with escape sequences.
+    pub struct ChunkIO {
+        input: Vec<&'static str>,
+        output: Vec<u8>,
+        size: (usize, usize)
+    }
+
+    impl RunIO for ChunkIO {
+        fn write(&mut self, w: Vec<u8>) -> Result<(), Error> {
+            self.output.extend(w);
+            Ok(())
+        }
+        fn size(&mut self) -> (usize, usize) {
+            self.size
+        }
+        fn read_byte(&mut self) -> Result<u8, Error> {
+            Err(Error::EndOfFile)
+        }
+
+        fn read_seq(&mut self) -> Result<Vec<u8>, Error> {
+            if !self.input.is_empty() {
+                Ok(self.input.remove(0).bytes().collect())
+            } else {
+                Err(Error::EndOfFile)
+            }
+        }
+    }
+
+    fn test_cfg(mode: EditMode) -> Config {
+        Config { encoding: Encoding::Ascii, mode: mode, ..Config::default() }
+    }
+
+    /// Runs the edit on `$io` with a history of `$lines`, setting up `$ctx` first.
+    macro_rules! run_test {
+        ($io:expr, $lines:expr, $cfg:expr) => {{
+            let h = history_of($lines);
+            let ctx = EditCtx::new("foo> ", h.store(), &$cfg);
+            run_edit(ctx, $io)
+        }};
+        ($io:expr, $lines:expr, $cfg:expr, |$ctx:ident| $setup:expr) => {{
+            let h = history_of($lines);
+            let mut $ctx = EditCtx::new("foo> ", h.store(), &$cfg);
+            $setup;
+            run_edit($ctx, $io)
+        }}
+    }
+
+    fn history_of(lines: &[&str]) -> History {
+        let mut h = History::new();
+        for line in lines {
+            h.push(line.to_string());
+        }
+        h
+    }
+
     #[test]
     fn error_eof_on_empty_input() {
         let mut io = TestIO { input: vec![], output: vec![] };
-        let h = History::new();
-        let ctx = EditCtx::new("foo> ", &h, ASCII, EditMode::Emacs);
-        assert_eq!(run_edit(ctx, &mut io), Err(Error::EndOfFile));
+        assert_eq!(run_test!(&mut io, &[], test_cfg(EditMode::Emacs)), Err(Error::EndOfFile));
     }
 
     #[test]
     fn ok_empty_after_return() {
         let mut io = TestIO { input: vec![13], output: vec![] };
-        let h = History::new();
-        let ctx = EditCtx::new("foo> ", &h, ASCII, EditMode::Emacs);
-        assert_eq!(run_edit(ctx, &mut io), Ok("".to_string()));
+        assert_eq!(run_test!(&mut io, &[], test_cfg(EditMode::Emacs)), Ok("".to_string()));
     }
 
     #[test]
     fn ok_ascii_after_return() {
         let mut io = TestIO { input: vec![65, 66, 67, 13], output: vec![] };
-        let h = History::new();
-        let ctx = EditCtx::new("foo> ", &h, ASCII, EditMode::Emacs);
-        assert_eq!(run_edit(ctx, &mut io), Ok("ABC".to_string()));
+        assert_eq!(run_test!(&mut io, &[], test_cfg(EditMode::Emacs)), Ok("ABC".to_string()));
     }
 
     /// Make sure integers don't overflow in vi mode when using large command counts.
@@ -137,9 +201,7 @@ mod test {
         }
         input_vec.push(13);
         let mut io = TestIO { input: input_vec, output: vec![] };
-        let h = History::new();
-        let ctx = EditCtx::new("foo> ", &h, ASCII, EditMode::Vi);
-        assert_eq!(run_edit(ctx, &mut io), Ok("".to_string()));
+        assert_eq!(run_test!(&mut io, &[], test_cfg(EditMode::Vi)), Ok("".to_string()));
     }
 
     macro_rules! vi_cmd_vec {
@@ -154,16 +216,473 @@ mod test {
     macro_rules! test_vi_cmds {
         ($cmd_str:expr, $result:expr) => {{
             let mut io = TestIO { input: vi_cmd_vec!($cmd_str), output: vec![] };
-            let h = History::new();
-            let ctx = EditCtx::new("foo> ", &h, ASCII, EditMode::Vi);
-            assert_eq!(run_edit(ctx, &mut io), Ok($result.to_owned()));
+            assert_eq!(run_test!(&mut io, &[], test_cfg(EditMode::Vi)), Ok($result.to_owned()));
         }}
     }
 
+    macro_rules! test_search {
+        ($mode:expr, $input:expr, $result:expr) => {{
+            let mut io = TestIO { input: vi_cmd_vec!($input), output: vec![] };
+            let result = run_test!(&mut io, &["git commit", "ls -l", "git push"], test_cfg($mode));
+            assert_eq!(result, Ok($result.to_owned()));
+        }}
+    }
+
+    /// Test incremental history search.
+    #[test]
+    fn reverse_search() {
+        test_search!(EditMode::Emacs, "\x12git\x0d", "git push");
+        test_search!(EditMode::Emacs, "\x12git\x12\x0d", "git commit");
+        test_search!(EditMode::Emacs, "\x12git\x12\x12\x0d", "git commit");
+        test_search!(EditMode::Emacs, "\x12git\x12\x13\x0d", "git push");
+        test_search!(EditMode::Emacs, "\x12ls\x7f\x7fgit c\x0d", "git commit");
+        test_search!(EditMode::Emacs, "typed\x12git\x07\x0d", "typed");
+        test_search!(EditMode::Emacs, "\x12ls\x1b!\x0d", "!ls -l");
+        test_search!(EditMode::Emacs, "\x12push\x01x\x0d", "xgit push");
+        test_search!(EditMode::Emacs, "\x12xyz\x0d", "");
+        test_search!(EditMode::Emacs, "\x12git\x1b\x10\x0d", "ls -l");
+        test_search!(EditMode::Emacs, "typed\x12git\x1b\x0e\x0d", "typed");
+        test_search!(EditMode::Vi, "\x12commit\x1bx\x0d", "git xcommit");
+    }
+
+    /// Test history browsing limited to entries starting with the text before the cursor.
+    #[test]
+    fn prefix_history() {
+        let test = |mode: EditMode, input: &str, result: &str| {
+            let mut io = TestIO { input: vi_cmd_vec!(input), output: vec![] };
+            let cfg = Config { history_prefix_search: true, ..test_cfg(mode) };
+            assert_eq!(run_test!(&mut io, &["git commit", "ls -l", "git push"], cfg), Ok(result.to_owned()));
+        };
+        test(EditMode::Emacs, "\x10\x10\x0d", "ls -l");
+        test(EditMode::Emacs, "l\x10\x0d", "ls -l");
+        test(EditMode::Emacs, "git\x10\x10\x0d", "git commit");
+        test(EditMode::Emacs, "git\x10\x10\x10\x0d", "git commit");
+        test(EditMode::Emacs, "git\x10\x10\x0e\x0d", "git push");
+        test(EditMode::Emacs, "git\x10\x0e\x0d", "git");
+        test(EditMode::Emacs, "git\x10X\x0d", "gitX push");
+        test(EditMode::Emacs, "gi x\x02\x02\x10\x0d", "git push");
+        test(EditMode::Emacs, "svn\x10\x0d", "svn");
+        test(EditMode::Vi, "git\x1bk0x\x0d", "it push");
+    }
+
+    #[test]
+    fn history_expansion() {
+        let test = |expansion: bool, input: &str, result: Result<&str, Error>| {
+            let mut io = TestIO { input: input.bytes().collect(), output: vec![] };
+            let cfg = Config { history_expansion: expansion, ..test_cfg(EditMode::Emacs) };
+            assert_eq!(run_test!(&mut io, &["ls -l /tmp"], cfg), result.map(|s| s.to_owned()));
+        };
+        test(true, "sudo !!\x0d", Ok("sudo ls -l /tmp"));
+        test(true, "cd !$\x0a", Ok("cd /tmp"));
+        test(true, "^-l^-a\x0d", Ok("ls -a /tmp"));
+        test(false, "sudo !!\x0d", Ok("sudo !!"));
+        test(true, "!cat\x0d", Err(Error::HistoryExpansion("!cat: event not found".to_owned())));
+
+        // M-^ expands in place, showing errors below the line
+        let input = vec!["!cat", "\x1b^", "\x7f", "\x7f", "\x7f", "!", "\x1b^", "\r"];
+        let mut io = ChunkIO { input: input, output: vec![], size: (80, 24) };
+        assert_eq!(run_test!(&mut io, &["ls -l /tmp"], test_cfg(EditMode::Emacs)), Ok("ls -l /tmp".to_owned()));
+        let output = String::from_utf8(io.output).unwrap();
+        assert!(output.contains("foo> !cat\x1b[0J\r\n!cat: event not found\x1b[1A\r\x1b[9C"));
+    }
+
+    /// Test vi history search with `/`, `?`, `n` and `N`.
+    #[test]
+    fn vi_search() {
+        test_search!(EditMode::Vi, "\x1b/git\x0d\x0d", "git push");
+        test_search!(EditMode::Vi, "\x1b/git\x0dn\x0d", "git commit");
+        test_search!(EditMode::Vi, "\x1b/git\x0dnN\x0d", "git push");
+        test_search!(EditMode::Vi, "\x1b2/git\x0d\x0d", "git commit");
+        test_search!(EditMode::Vi, "\x1b/git\x0d3n\x0d", "git commit");
+        test_search!(EditMode::Vi, "\x1b/git\x0d/\x0d\x0d", "git commit");
+        test_search!(EditMode::Vi, "\x1b/ls\x0d?git\x0d\x0d", "git push");
+        test_search!(EditMode::Vi, "\x1b?git\x0d\x0d", "");
+        test_search!(EditMode::Vi, "\x1b/xyz\x0d\x0d", "");
+        test_search!(EditMode::Vi, "abc\x1b/ls\x1b\x0d", "abc");
+        test_search!(EditMode::Vi, "\x1b/\x7fk\x0d", "git push");
+        test_search!(EditMode::Vi, "\x1b/ls\x0dx\x0d", "s -l");
+        test_search!(EditMode::Vi, "abc\x1b/git\x0diX\x0d", "Xgit push");
+    }
+
+    /// Test emacs history jumps and yanking words of previous lines.
+    #[test]
+    fn emacs_history_args() {
+        let test = |input: Vec<&'static str>, result: &str| {
+            let mut io = ChunkIO { input: input, output: vec![], size: (80, 24) };
+            let lines = ["git commit -m msg", "ls -l /tmp", "echo a b c"];
+            assert_eq!(run_test!(&mut io, &lines, test_cfg(EditMode::Emacs)), Ok(result.to_owned()));
+        };
+        test(vec!["x ", "\x1b.", "\r"], "x c");
+        test(vec!["x ", "\x1b.", "\x1b.", "\r"], "x /tmp");
+        test(vec!["x ", "\x1b.", "\x1b.", "\x1b.", "\x1b.", "\r"], "x msg");
+        test(vec!["x ", "\x1b.", "y", "\x1b.", "\r"], "x cyc");
+        test(vec!["x ", "\x1b0", "\x1b.", "\r"], "x echo");
+        test(vec!["x ", "\x1b.", "\x1b1", "\x1b.", "\r"], "x -l");
+        test(vec!["x ", "\x1b\x19", "\r"], "x a");
+        test(vec!["x ", "\x1b2", "\x1b\x19", "\r"], "x b");
+        test(vec!["x ", "\x1b9", "\x1b\x19", "\r"], "x ");
+        test(vec!["\x1b<", "\r"], "git commit -m msg");
+        test(vec!["typed", "\x1b<", "\x1b>", "\r"], "typed");
+        test(vec!["\x10", "\x1b>", "\r"], "");
+
+        // words with combining marks are replaced as a whole
+        let mut io = ChunkIO { input: vec!["x ", "\x1b.", "\x1b.", "\r"], output: vec![], size: (80, 24) };
+        let cfg = Config { encoding: Encoding::Utf8, ..test_cfg(EditMode::Emacs) };
+        assert_eq!(run_test!(&mut io, &["ls ab", "echo e\u{301}e\u{301}"], cfg), Ok("x ab".to_owned()));
+    }
+
+    fn complete_words(line: &str, pos: usize) -> Completion {
+        let start = line[..pos].rfind(' ').map_or(0, |i| i + 1);
+        let candidates = ["foo", "foobar", "foobaz", "quux"].iter()
+            .filter(|c| c.starts_with(&line[start..pos]))
+            .map(|c| c.to_string())
+            .collect();
+        Completion::new(start..pos, candidates)
+    }
+
+    /// Test completion of the word before the cursor.
+    #[test]
+    fn tab_completion() {
+        let test = |mode: EditMode, input: &str, result: &str| -> Vec<u8> {
+            let mut io = TestIO { input: vi_cmd_vec!(input), output: vec![] };
+            let edited = run_test!(&mut io, &[], test_cfg(mode), |ctx| ctx.set_completer(&complete_words));
+            assert_eq!(edited, Ok(result.to_owned()));
+            io.output
+        };
+        test(EditMode::Emacs, "q\t\x0d", "quux");
+        test(EditMode::Emacs, "fo\t\x0d", "foo");
+        test(EditMode::Emacs, "foob\t\x0d", "fooba");
+        test(EditMode::Emacs, "x foob\t\x0d", "x fooba");
+        test(EditMode::Emacs, "foob x\x02\x02\t\x0d", "fooba x");
+        test(EditMode::Emacs, "z\t\t\x0d", "z");
+        test(EditMode::Vi, "q\t\x0d", "quux");
+
+        let listed = |output: &[u8]| contains(output, "foobar  foobaz");
+        assert!(listed(&test(EditMode::Emacs, "fooba\t\t\x0d", "fooba")));
+        assert!(!listed(&test(EditMode::Emacs, "fooba\tx\t\x0d", "foobax")));
+    }
+
+    /// Test ignoring completions that would split a character.
+    #[test]
+    fn invalid_completion_span() {
+        let split = |_: &str, _: usize| Completion::new(1..2, vec!["x", "y"]);
+        for &menu_complete in [false, true].iter() {
+            let mut io = ChunkIO { input: vec!["é", "\t", "\t", "\r"], output: vec![], size: (80, 24) };
+            let cfg = Config { encoding: Encoding::Utf8, menu_complete: menu_complete, ..test_cfg(EditMode::Emacs) };
+            assert_eq!(run_test!(&mut io, &[], cfg, |ctx| ctx.set_completer(&split)), Ok("é".to_owned()));
+        }
+    }
+
+    /// Test cycling through completions in place.
+    #[test]
+    fn menu_completion() {
+        let test = |mode: EditMode, input: &str, result: &str| -> Vec<u8> {
+            let mut io = TestIO { input: vi_cmd_vec!(input), output: vec![] };
+            let cfg = Config { menu_complete: true, ..test_cfg(mode) };
+            assert_eq!(run_test!(&mut io, &[], cfg, |ctx| ctx.set_completer(&complete_words)), Ok(result.to_owned()));
+            io.output
+        };
+        assert!(contains(&test(EditMode::Emacs, "fo\t\x0d\x0d", "foo"), "\x1b[7mfoo\x1b[0m     foobar"));
+        test(EditMode::Emacs, "fo\t\t\x0d\x0d", "foobar");
+        test(EditMode::Emacs, "fo\t\t\t\t\x0d\x0d", "foo");
+        test(EditMode::Emacs, "fo\t\t\x07\x0d", "fo");
+        test(EditMode::Emacs, "fo\t\tx\x0d", "foobarx");
+        test(EditMode::Emacs, "q\t\x0d", "quux");
+        test(EditMode::Emacs, "x\t\x0d", "x");
+        test(EditMode::Vi, "fo\x0e\x0e\x0d\x0d", "foobar");
+        test(EditMode::Vi, "fo\x10\x0d\x0d", "foobaz");
+        test(EditMode::Vi, "fo\t\x1bx\x0d", "fo");
+
+        // three candidates fit into two columns of a width of 20
+        let test = |input: Vec<&'static str>, result: &str| {
+            let mut io = ChunkIO { input: input, output: vec![], size: (20, 24) };
+            let edited = run_test!(&mut io, &[], test_cfg(EditMode::Emacs), |ctx| ctx.set_completer(&complete_words));
+            assert_eq!(edited, Ok(result.to_owned()));
+        };
+        test(vec!["fo", "\x1b[Z", "\r", "\r"], "foobaz");
+        test(vec!["fo", "\x1b[Z", "\x1b[Z", "\r", "\r"], "foobar");
+        test(vec!["fo", "\x1b[Z", "\t", "\r", "\r"], "foo");
+        test(vec!["fo", "\t", "\t", "\r"], "foo");
+        test(vec!["fo", "\x1b[Z", "\x1b[C", "\x1b[C", "\r", "\r"], "foobar");
+        test(vec!["fo", "\x1b[Z", "\x1b[D", "\r", "\r"], "foobar");
+        test(vec!["fo", "\x1b[Z", "\x1b[B", "\r", "\r"], "foo");
+        test(vec!["fo", "\x1b[Z", "\x1b[B", "\x1b[B", "\r", "\r"], "foobaz");
+        test(vec!["fo", "\x1b[Z", "\x1b[D", "\x1b[B", "\r", "\r"], "foobar");
+        test(vec!["fo", "\x1b[Z", "\x1b[B", "\x1b[A", "\r", "\r"], "foobaz");
+    }
+
+    fn contains(output: &[u8], s: &str) -> bool {
+        output.windows(s.len()).any(|w| w == s.as_bytes())
+    }
+
+    /// Test listing many completions in columns, page by page.
+    #[test]
+    fn completion_listing() {
+        let test = |count: usize, input: &str, result: &str| -> Vec<u8> {
+            let mut io = TestIO { input: vi_cmd_vec!(input), output: vec![] };
+            let complete = move |_: &str, pos: usize| {
+                Completion::new(0..pos, (0..count).map(|i| format!("item{:03}", i)).collect())
+            };
+            assert_eq!(run_test!(&mut io, &[], test_cfg(EditMode::Emacs), |ctx| ctx.set_completer(&complete)),
+                       Ok(result.to_owned()));
+            io.output
+        };
+        // eight columns fit into the default width of 80
+        let output = test(30, "i\t\t\x0d", "item0");
+        assert!(contains(&output, "\r\nitem000  item001  item002"));
+        assert!(contains(&output, "item029\r\n\rfoo> item0"));
+        assert!(!contains(&output, "Display all"));
+
+        let output = test(300, "i\t\tn\x0d", "item");
+        assert!(contains(&output, "Display all 300 possibilities? (y or n)"));
+        assert!(!contains(&output, "item000"));
+
+        // the first page takes all but one of the default 24 rows
+        let output = test(300, "i\t\tyq\x0d", "item");
+        assert!(contains(&output, "item183\r\n--More--\r\x1b[K\rfoo> item"));
+        assert!(!contains(&output, "item184"));
+
+        let output = test(300, "i\t\ty\x0dq\x0d", "item");
+        assert!(contains(&output, "item191"));
+        assert!(!contains(&output, "item192"));
+
+        let output = test(300, "i\t\ty \x0d", "item");
+        assert!(contains(&output, "item299\r\n\rfoo> item"));
+    }
+
+    /// Test the fuzzy history finder.
+    #[test]
+    fn fuzzy_search() {
+        let test = |input: &str, result: &str| {
+            let mut io = TestIO { input: vi_cmd_vec!(input), output: vec![] };
+            // TestIO only returns single bytes, so start with the whole M-r sequence
+            let edited = run_test!(&mut io, &["git commit", "ls -l", "git push"], test_cfg(EditMode::Emacs),
+                                   |ctx| ctx.fill(b"\x1br".iter().cloned()));
+            assert_eq!(edited, Ok(result.to_owned()));
+        };
+        test("\x0d\x0d", "git push");
+        test("gc\x0d\x0d", "git commit");
+        test("g\x0e\x0d\x0d", "git commit");
+        test("g\x0e\x10\x0d\x0d", "git push");
+        test("gcx\x7f\x7f\x7fll\x0dX\x0d", "ls -lX");
+        test("git\x0d\x10\x0d", "ls -l");
+        test("xyz\x0d\x0d", "");
+        test("git\x07\x0d", "");
+    }
+
+    /// Test accepting suggestions from the history.
+    #[test]
+    fn autosuggestions() {
+        use hint::HistoryHinter;
+        let lines = ["git commit -m msg", "git checkout master"];
+        let test = |mode: EditMode, input: &str, result: &str| -> Vec<u8> {
+            let mut io = TestIO { input: vi_cmd_vec!(input), output: vec![] };
+            assert_eq!(run_test!(&mut io, &lines, test_cfg(mode), |ctx| ctx.set_hinter(&HistoryHinter)),
+                       Ok(result.to_owned()));
+            io.output
+        };
+        let output = test(EditMode::Emacs, "git c\x0d", "git c");
+        assert!(contains(&output, "git c\x1b[2mheckout master\x1b[0m"));
+        // the hint is erased when the line is accepted
+        assert!(output.ends_with(b"\x1b[0K"));
+        test(EditMode::Emacs, "git c\x05\x0d", "git checkout master");
+        test(EditMode::Emacs, "git co\x05\x0d", "git commit -m msg");
+        test(EditMode::Emacs, "git c\x02\x05\x0d", "git c");
+        test(EditMode::Emacs, "git c\x06\x0d", "git checkout master");
+
+        let test = |input: Vec<&'static str>, result: &str| {
+            let mut io = ChunkIO { input: input, output: vec![], size: (80, 24) };
+            assert_eq!(run_test!(&mut io, &lines, test_cfg(EditMode::Emacs), |ctx| ctx.set_hinter(&HistoryHinter)),
+                       Ok(result.to_owned()));
+        };
+        test(vec!["git c", "\x1bf", "\r"], "git checkout");
+        test(vec!["git c", "\x1bf", "\x1bf", "\r"], "git checkout master");
+        test(vec!["git c", "\x1b[C", "\r"], "git checkout master");
+        test(vec!["git c", "\x01", "\x1bf", "\r"], "git c");
+    }
+
+    /// Test that the line is highlighted again after every edit.
+    #[test]
+    fn highlighting() {
+        use highlight::Span;
+        use style::Style;
+        let keywords = |line: &str| -> Vec<Span> {
+            line.match_indices("select")
+                .map(|(i, m)| Span::new(i..i + m.len(), Style { bold: true, ..Style::new() }))
+                .collect()
+        };
+        let mut io = TestIO { input: vi_cmd_vec!("selet\x02c\x06x\x7f\x0d"), output: vec![] };
+        let edited = run_test!(&mut io, &[], test_cfg(EditMode::Emacs), |ctx| ctx.set_highlighter(&keywords));
+        assert_eq!(edited, Ok("select".to_owned()));
+        assert!(contains(&io.output, "foo> selet\x1b[0K"));
+        assert!(contains(&io.output, "foo> \x1b[1mselect\x1b[0m\x1b[0K\r\x1b[10C"));
+        assert!(contains(&io.output, "foo> \x1b[1mselect\x1b[0mx\x1b[0K\r\x1b[12C"));
+        assert!(contains(&io.output, "foo> \x1b[1mselect\x1b[0m\x1b[0K\r\x1b[11C"));
+
+        // a span over the whole input leaves blank lines alone
+        let quoted = |line: &str| vec![Span::new(0..line.len(), Style { bold: true, ..Style::new() })];
+        let mut io = ChunkIO { input: vec!["'a", "\x1b\r", "\x1b\r", "b'", "\r"], output: vec![], size: (80, 24) };
+        let edited = run_test!(&mut io, &[], test_cfg(EditMode::Emacs), |ctx| ctx.set_highlighter(&quoted));
+        assert_eq!(edited, Ok("'a\n\nb'".to_owned()));
+    }
+
+    /// Test jumping to and highlighting matching brackets in emacs mode.
+    #[test]
+    fn matching_brackets() {
+        let test = |input: Vec<&'static str>, result: &str| {
+            let mut io = ChunkIO { input: input, output: vec![], size: (80, 24) };
+            assert_eq!(run_test!(&mut io, &[], test_cfg(EditMode::Emacs)), Ok(result.to_owned()));
+        };
+        test(vec!["(a b)", "\x01", "\x1b\x06", "c", "\r"], "(a b)c");
+        test(vec!["(a b)", "\x1b\x02", "c", "\r"], "c(a b)");
+        test(vec!["(a b)", "\x1b\x02", "\x1b\x02", "\x1b\x06", "\x1b\x06", "c", "\r"], "(a b)c");
+
+        let mut io = TestIO { input: vi_cmd_vec!("[(a)]\x01\x0d"), output: vec![] };
+        assert_eq!(run_test!(&mut io, &[], test_cfg(EditMode::Emacs)), Ok("[(a)]".to_owned()));
+        assert!(contains(&io.output, "foo> [\x1b[7m(\x1b[0ma)\x1b[0K"));
+        assert!(contains(&io.output, "foo> [(a)\x1b[7m]\x1b[0m\x1b[0K\r\x1b[5C"));
+    }
+
+    /// Test that the cursor is placed by the display width of the prompt.
+    #[test]
+    fn styled_prompt() {
+        let test = |prompt: &str, drawn: &str| {
+            let mut io = TestIO { input: vi_cmd_vec!("ab\x0d"), output: vec![] };
+            let h = History::new();
+            let ctx = EditCtx::new(prompt, h.store(), &test_cfg(EditMode::Emacs));
+            assert_eq!(run_edit(ctx, &mut io), Ok("ab".to_owned()));
+            assert!(contains(&io.output, drawn));
+        };
+        test("\x1b[1;34mfoo\x1b[0m> ", "\r\x1b[1;34mfoo\x1b[0m> ab\x1b[0K\r\x1b[7C");
+        test("日本> ", "\r日本> ab\x1b[0K\r\x1b[8C");
+        test("\x01\x1b]0;t\x07\x02> ", "\r\x1b]0;t\x07> ab\x1b[0K\r\x1b[4C");
+    }
+
+    /// Test the right prompt, which is hidden when the line runs into it.
+    #[test]
+    fn right_prompt() {
+        let test = |input: &str, transient: bool| -> Vec<u8> {
+            let mut io = TestIO { input: vi_cmd_vec!(input), output: vec![] };
+            let cfg = Config {
+                right_prompt: Some("\x1b[2m12:00\x1b[0m".to_owned()),
+                transient_right_prompt: transient,
+                ..test_cfg(EditMode::Emacs)
+            };
+            assert_eq!(run_test!(&mut io, &[], cfg), Ok(input.trim_end_matches('\r').to_owned()));
+            io.output
+        };
+        // the default width is 80, so the right prompt starts at column 74
+        let drawn = "\r\x1b[74C\x1b[2m12:00\x1b[0m";
+        let output = test("ab\r", false);
+        assert!(contains(&output, &format!("foo> ab\x1b[0K{}\r\x1b[7C", drawn)));
+        assert!(output.ends_with(b"\r\x1b[7C"));
+        let output = test("ab\r", true);
+        assert!(output.ends_with(b"\r\x1b[7C\x1b[0K"));
+
+        let long = format!("{}\r", "x".repeat(69));
+        let output = test(&long, true);
+        assert!(contains(&output, &format!("{}\x1b[0K{}", "x".repeat(68), drawn)));
+        assert!(!contains(&output, &format!("{}\x1b[0K{}", "x".repeat(69), drawn)));
+        // nothing is left to erase once the right prompt is hidden
+        assert!(output.ends_with(b"\r\x1b[74C"));
+    }
+
+    /// Test that only the last line of a prompt is drawn again while editing.
+    #[test]
+    fn multi_line_prompt() {
+        let mut io = TestIO { input: vi_cmd_vec!("ab\x0cc\x0d"), output: vec![] };
+        let h = History::new();
+        let ctx = EditCtx::new("~/src\nfoo> ", h.store(), &test_cfg(EditMode::Emacs));
+        assert_eq!(run_edit(ctx, &mut io), Ok("abc".to_owned()));
+        let output = String::from_utf8(io.output).unwrap();
+        assert!(output.starts_with("~/src\r\n\rfoo> \x1b[0K"));
+        assert!(output.contains("\rfoo> a\x1b[0K"));
+        // C-l draws the whole prompt again
+        assert!(output.contains("\x1b[H\x1b[2J~/src\r\n\rfoo> ab\x1b[0K"));
+        assert_eq!(output.matches("~/src").count(), 2);
+    }
+
+    /// Test continuing incomplete lines and keeping invalid ones.
+    #[test]
+    fn validation() {
+        use validate::ValidationResult;
+        let balanced = |line: &str| -> ValidationResult {
+            if line.contains('!') {
+                ValidationResult::Invalid("no bangs".to_owned())
+            } else if line.matches('(').count() > line.matches(')').count() {
+                ValidationResult::Incomplete
+            } else {
+                ValidationResult::Valid
+            }
+        };
+        let test = |input: &str, result: &str| -> String {
+            let mut io = TestIO { input: vi_cmd_vec!(input), output: vec![] };
+            let cfg = Config {
+                continuation_prompt: ".. ".to_owned(),
+                match_brackets: false,
+                ..test_cfg(EditMode::Emacs)
+            };
+            assert_eq!(run_test!(&mut io, &[], cfg, |ctx| ctx.set_validator(&balanced)), Ok(result.to_owned()));
+            String::from_utf8(io.output).unwrap()
+        };
+        let output = test("(a\rb)\r", "(a\nb)");
+        assert!(output.contains("\rfoo> (a\x1b[0K\r\n.. b)\x1b[0K\r\x1b[5C"));
+        let output = test("(a\r(b\r))\r", "(a\n(b\n))");
+        assert!(output.contains("\x1b[2A\rfoo> (a\x1b[0K\r\n.. (b\x1b[0K\r\n.. ))\x1b[0K\r\x1b[5C"));
+        // the cursor ends up after the last line
+        let output = test("(a\rb)\x01\r", "(a\nb)");
+        assert!(output.ends_with("\x1b[1B\r\x1b[5C\x1b[0K"));
+
+        let output = test("x!\r\x7f\r", "x");
+        assert!(output.contains("foo> x!\x1b[0J\r\nno bangs\x1b[1A\r\x1b[7C"));
+        assert!(output.contains("foo> x\x1b[0J\r\x1b[6C"));
+    }
+
+    /// Test lines wrapping at the width of the terminal.
+    #[test]
+    fn wrapped_line() {
+        let test = |input: String| -> String {
+            let mut io = TestIO { input: input.clone().into_bytes(), output: vec![] };
+            assert_eq!(run_test!(&mut io, &[], test_cfg(EditMode::Emacs)),
+                       Ok(input.trim_end_matches(|c| c < ' ').to_owned()));
+            String::from_utf8(io.output).unwrap()
+        };
+        // the cursor moves back to the first row and down after the text when done
+        let output = test(format!("{}\x01\r", "x".repeat(100)));
+        assert!(output.contains(&format!("\x1b[1A\rfoo> {}\x1b[0K\x1b[1A\r\x1b[5C", "x".repeat(100))));
+        assert!(output.ends_with("\x1b[1B\r\x1b[25C\x1b[0K"));
+        // a full row leaves the cursor at the start of the next one
+        let output = test(format!("{}\r", "x".repeat(75)));
+        assert!(output.ends_with(&format!("\rfoo> {}\r\n\x1b[0K\r", "x".repeat(75))));
+    }
+
+    /// Test moving between the lines of the buffer before browsing history.
+    #[test]
+    fn multi_line_editing() {
+        let test = |mode: EditMode, input: Vec<&'static str>, result: &str| {
+            let mut io = ChunkIO { input: input, output: vec![], size: (80, 24) };
+            let cfg = Config { match_brackets: false, ..test_cfg(mode) };
+            assert_eq!(run_test!(&mut io, &["old"], cfg), Ok(result.to_owned()));
+        };
+        test(EditMode::Emacs, vec!["ab", "\x1b\r", "cd", "\x1b[A", "x", "\r"], "abx\ncd");
+        test(EditMode::Emacs, vec!["ab", "\x1b\r", "cd", "\x1b[A", "\x1b[A", "\r"], "old");
+        test(EditMode::Emacs, vec!["ab", "\x1b\r", "cd", "\x1b[A", "\x1b[A", "\x1b[B", "\r"], "ab\ncd");
+        test(EditMode::Emacs, vec!["ab", "\x1b\r", "cd", "\x02", "\x10", "\x0e", "x", "\r"], "ab\ncxd");
+        test(EditMode::Vi, vec!["a", "\x1b\r", "b", "\x1b\r", "c", "\x1b", "2", "k", "i", "x", "\r"], "xa\nb\nc");
+        test(EditMode::Vi, vec!["a", "\x1b\r", "b", "\x1b", "k", "j", "i", "x", "\r"], "a\nxb");
+
+        // moving right at the end of a multi-line buffer stays there
+                test(EditMode::Emacs, vec!["a", "\x1b\r", "b", "\x01", "\x05", "\x1b[C", "\r"], "a\nb");
+        let mut io = ChunkIO { input: vec!["\x10", "\x10", "\x1b[C", "\r"], output: vec![], size: (80, 24) };
+        assert_eq!(run_test!(&mut io, &["a\nb", "xyzw"], test_cfg(EditMode::Emacs)), Ok("a\nb".to_owned()));
+    }
+
     /// Test some vi commands.
     #[test]
     fn vi_commands() {
         test_vi_cmds!("abc 123\x1bbdw\x0d", "abc ");
+        // searching is no motion, so it cancels the delete
+        test_vi_cmds!("abc\x1bd/x\x0d", "ab");
         test_vi_cmds!("Everything except the last char will be deleted.\x1bd0\x0d", ".");
         test_vi_cmds!("delete everything\x1b0d$\x0d", "");
         test_vi_cmds!("abc\x1b03r \x0d", "   ");
@@ -191,5 +710,9 @@ mod test {
         test_vi_cmds!("delete everything\x1bdd\x0d", "");
         test_vi_cmds!("delete everything\x1bccchange everything\x0d", "change everything");
         test_vi_cmds!("this is a test\x1bbc2T some \x0d", "this is some test");
+        test_vi_cmds!("f(a, b) x\x1b0%x\x0d", "f(a, b x");
+        test_vi_cmds!("f(a, (b)) x\x1b0d%\x0d", " x");
+        test_vi_cmds!("call(a) z\x1bF)c%(b)\x0d", "call(b) z");
+        test_vi_cmds!("echo \"(\" (x)\x1b0%x\x0d", "echo \"(\" (x");
     }
 }
@@ -0,0 +1,111 @@
+/// A terminal color.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Color {
+    Black,
+    Red,
+    Green,
+    Yellow,
+    Blue,
+    Magenta,
+    Cyan,
+    White,
+    /// One of the 256 colors of the extended palette.
+    Fixed(u8),
+    /// A true color, for terminals supporting them.
+    Rgb(u8, u8, u8)
+}
+
+impl Color {
+    fn sgr(&self, base: u8) -> String {
+        match *self {
+            Color::Black   => format!("{}", base),
+            Color::Red     => format!("{}", base + 1),
+            Color::Green   => format!("{}", base + 2),
+            Color::Yellow  => format!("{}", base + 3),
+            Color::Blue    => format!("{}", base + 4),
+            Color::Magenta => format!("{}", base + 5),
+            Color::Cyan    => format!("{}", base + 6),
+            Color::White   => format!("{}", base + 7),
+            Color::Fixed(n) => format!("{};5;{}", base + 8, n),
+            Color::Rgb(r, g, b) => format!("{};2;{};{};{}", base + 8, r, g, b)
+        }
+    }
+}
+
+/// The colors and attributes text is drawn with.
+#[derive(Copy, Clone, Debug, PartialEq, Default)]
+pub struct Style {
+    pub fg: Option<Color>,
+    pub bg: Option<Color>,
+    pub bold: bool,
+    pub dim: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub inverted: bool
+}
+
+impl Style {
+    /// Returns the style of text drawn without any escape sequences.
+    pub fn new() -> Style {
+        Style::default()
+    }
+
+    pub fn fg(color: Color) -> Style {
+        Style { fg: Some(color), ..Style::new() }
+    }
+
+    pub fn is_plain(&self) -> bool {
+        *self == Style::new()
+    }
+
+    /// Returns the escape sequence that switches to this style, which is
+    /// empty for the plain style.
+    pub fn sgr(&self) -> String {
+        let mut params = Vec::new();
+        if self.bold {
+            params.push("1".to_owned());
+        }
+        if self.dim {
+            params.push("2".to_owned());
+        }
+        if self.italic {
+            params.push("3".to_owned());
+        }
+        if self.underline {
+            params.push("4".to_owned());
+        }
+        if self.inverted {
+            params.push("7".to_owned());
+        }
+        if let Some(fg) = self.fg {
+            params.push(fg.sgr(30));
+        }
+        if let Some(bg) = self.bg {
+            params.push(bg.sgr(40));
+        }
+        if params.is_empty() {
+            String::new()
+        } else {
+            format!("\x1b[{}m", params.join(";"))
+        }
+    }
+
+    /// Returns the text wrapped in the escape sequences of this style.
+    pub fn paint(&self, text: &str) -> String {
+        if self.is_plain() {
+            text.to_owned()
+        } else {
+            format!("{}{}\x1b[0m", self.sgr(), text)
+        }
+    }
+}
+
+#[test]
+fn style_sgr() {
+    assert_eq!(Style::new().sgr(), "");
+    assert_eq!(Style::new().paint("x"), "x");
+    assert_eq!(Style::fg(Color::Red).paint("x"), "\x1b[31mx\x1b[0m");
+    let style = Style { bold: true, bg: Some(Color::Fixed(208)), ..Style::fg(Color::Rgb(1, 2, 3)) };
+    assert_eq!(style.sgr(), "\x1b[1;38;2;1;2;3;48;5;208m");
+    assert_eq!(Style { dim: true, underline: true, ..Style::new() }.sgr(), "\x1b[2;4m");
+}
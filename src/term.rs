@@ -26,6 +26,9 @@ impl<'a> RunIO for TermIO<'a> {
         let read = try!(self.in_term.read_byte());
         read.ok_or(Error::EndOfFile)
     }
+    fn size(&mut self) -> (usize, usize) {
+        self.in_term.size().unwrap_or((80, 24))
+    }
     fn read_seq(&mut self) -> Result<Vec<u8>, Error> {
         let read = try!(self.in_term.read_seq());
         if read.len() == 0 {
@@ -78,6 +81,25 @@ impl Drop for RawMode {
     }
 }
 
+#[repr(C)]
+struct WinSize {
+    ws_row: u16,
+    ws_col: u16,
+    ws_xpixel: u16,
+    ws_ypixel: u16
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const TIOCGWINSZ: libc::c_int = 0x5413;
+
+#[cfg(any(target_os = "macos",
+          target_os = "ios",
+          target_os = "freebsd",
+          target_os = "dragonfly",
+          target_os = "netbsd",
+          target_os = "openbsd"))]
+const TIOCGWINSZ: libc::c_ulong = 0x40087468;
+
 pub struct Term {
     in_fd: RawFd,
     out_fd: RawFd
@@ -105,6 +127,17 @@ impl Term {
         unsafe { libc::isatty(self.out_fd) != 0 }
     }
 
+    /// Returns the number of columns and rows of the terminal.
+    pub fn size(&self) -> Option<(usize, usize)> {
+        let mut ws = WinSize { ws_row: 0, ws_col: 0, ws_xpixel: 0, ws_ypixel: 0 };
+        let res = unsafe { libc::funcs::bsd44::ioctl(self.out_fd, TIOCGWINSZ, &mut ws) };
+        if res == -1 || ws.ws_col == 0 {
+            None
+        } else {
+            Some((ws.ws_col as usize, ws.ws_row as usize))
+        }
+    }
+
     pub fn acquire_io<'a>(&'a mut self) -> Result<TermIO<'a>, Error> {
         if !self.is_a_tty() {
             return Err(Error::from(nix::Error::from_errno(Errno::ENOTTY)));
@@ -0,0 +1,20 @@
+/// Whether a line is ready to be accepted.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ValidationResult {
+    Valid,
+    /// More lines are needed, so `Enter` starts a new one.
+    Incomplete,
+    /// The line is kept for editing, with the message shown below it.
+    Invalid(String)
+}
+
+/// Decides whether `Enter` accepts the line, asked every time it is pressed.
+pub trait Validator {
+    fn validate(&self, line: &str) -> ValidationResult;
+}
+
+impl<F> Validator for F where F: Fn(&str) -> ValidationResult {
+    fn validate(&self, line: &str) -> ValidationResult {
+        self(line)
+    }
+}